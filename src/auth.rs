@@ -7,14 +7,13 @@ use axum::{
 };
 use jsonwebtoken::{decode, decode_header, Validation};
 use jwks::Jwks;
-use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::{event, Level};
 
-use crate::state::AppState;
+use crate::{domain::DomainError, state::AppState};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: String,
     pub aud: Value,
@@ -25,11 +24,16 @@ pub struct Claims {
     pub scope: String,
 }
 
+// The reason is only logged; callers get the same answer for every bad token
+fn unauthorized() -> DomainError {
+    DomainError::Unauthorized(String::from("A valid bearer token is required"))
+}
+
 pub async fn authentication_middleware(
     State(state): State<Arc<AppState>>,
-    request: Request,
+    mut request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, DomainError> {
     // Get the Authorization header
     match request.headers().get("Authorization") {
         Some(auth_header) => {
@@ -39,18 +43,12 @@ pub async fn authentication_middleware(
                     // Pull the token from the final part of the string 'Bearer <token>'
                     let token = auth_header_str.split_whitespace().last();
 
-                    let token = match token {
-                        Some(t) => t,
-                        None => "",
-                    };
+                    let token = token.unwrap_or_default();
 
                     // Decode the header of the JWT which contains the 'kid'
                     match decode_header(token) {
                         Ok(decoded_token) => {
-                            let kid = match decoded_token.kid {
-                                Some(k) => k,
-                                None => String::new(),
-                            };
+                            let kid = decoded_token.kid.unwrap_or_default();
 
                             // Retrieve the JWKS
                             let jwks_url = format!("{}/.well-known/jwks.json", state.auth0_domain);
@@ -72,17 +70,15 @@ pub async fn authentication_middleware(
                                                 &validation,
                                             ) {
                                                 Ok(token_data) => {
-                                                    match token_data.claims.aud {
+                                                    match &token_data.claims.aud {
                                                         Value::String(single_aud) => {
-                                                            if state.auth0_audience != single_aud {
+                                                            if &state.auth0_audience != single_aud {
                                                                 event!(
                                                                     Level::WARN,
                                                                     "Invalid audience: {}!",
                                                                     single_aud
                                                                 );
-                                                                return Err(
-                                                                    StatusCode::UNAUTHORIZED,
-                                                                );
+                                                                return Err(unauthorized());
                                                             }
                                                         }
                                                         Value::Array(multiple_aud) => {
@@ -91,14 +87,13 @@ pub async fn authentication_middleware(
                                                             for entry in multiple_aud {
                                                                 match entry {
                                                                     Value::String(s) => {
-                                                                        if state.auth0_audience == s
+                                                                        if &state.auth0_audience
+                                                                            == s
                                                                         {
                                                                             aud_found = true;
                                                                         }
                                                                     }
-                                                                    _ => return Err(
-                                                                        StatusCode::UNAUTHORIZED,
-                                                                    ),
+                                                                    _ => return Err(unauthorized()),
                                                                 }
                                                             }
 
@@ -107,53 +102,84 @@ pub async fn authentication_middleware(
                                                                     Level::WARN,
                                                                     "Invalid audience!"
                                                                 );
-                                                                return Err(
-                                                                    StatusCode::UNAUTHORIZED,
-                                                                );
+                                                                return Err(unauthorized());
                                                             }
                                                         }
-                                                        _ => return Err(StatusCode::UNAUTHORIZED),
+                                                        _ => return Err(unauthorized()),
                                                     }
 
                                                     event!(
                                                         Level::TRACE,
                                                         "Auth middleware successful!"
                                                     );
-                                                    return Ok(next.run(request).await);
+                                                    // Make the claims available to downstream authorization checks
+                                                    request
+                                                        .extensions_mut()
+                                                        .insert(token_data.claims);
+                                                    Ok(next.run(request).await)
                                                 }
                                                 Err(e) => {
                                                     event!(Level::WARN, "Failed to decode token using decode key from jwk: {}!", e);
-                                                    return Err(StatusCode::UNAUTHORIZED);
+                                                    Err(unauthorized())
                                                 }
                                             }
                                         }
                                         None => {
                                             event!(Level::WARN, "Failed to get JWK from JWKS!");
-                                            return Err(StatusCode::UNAUTHORIZED);
+                                            Err(unauthorized())
                                         }
                                     }
                                 }
                                 Err(_) => {
                                     event!(Level::WARN, "Failed to fetch jwks!");
-                                    return Err(StatusCode::UNAUTHORIZED);
+                                    Err(unauthorized())
                                 }
                             }
                         }
                         Err(_) => {
                             event!(Level::WARN, "Failed to decode token header!");
-                            return Err(StatusCode::UNAUTHORIZED);
+                            Err(unauthorized())
                         }
                     }
                 }
                 Err(_) => {
                     event!(Level::WARN, "Auth header not formatted correctly!");
-                    return Err(StatusCode::UNAUTHORIZED);
+                    Err(unauthorized())
                 }
             }
         }
         None => {
             event!(Level::WARN, "No auth header found!");
-            return Err(StatusCode::UNAUTHORIZED);
+            Err(unauthorized())
+        }
+    }
+}
+
+pub async fn admin_authorization_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, DomainError> {
+    // The claims are placed on the request by the authentication middleware
+    match request.extensions().get::<Claims>() {
+        Some(claims) => {
+            if claims
+                .scope
+                .split_whitespace()
+                .any(|scope| scope == state.auth0_admin_scope)
+            {
+                event!(Level::TRACE, "Admin authorization successful!");
+                Ok(next.run(request).await)
+            } else {
+                event!(Level::WARN, "Missing admin scope for {}!", claims.sub);
+                Err(DomainError::Forbidden(String::from(
+                    "Admin scope is required for this operation",
+                )))
+            }
+        }
+        None => {
+            event!(Level::WARN, "No claims found on request!");
+            Err(unauthorized())
         }
     }
 }
@@ -1,14 +1,27 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
-use crate::uow::{ProductUnitOfWork, UnitOfWork};
+use crate::clock::current_utc_millis;
+use crate::uow::UnitOfWork;
 use crate::{
-    domain::Product,
-    dtos::{CreateProductResponse, EmptyResponse, GetProductsResponse, ProductResponse, Response},
+    domain::{
+        DomainError, FacetBucket, ProcessedMessage, Product, ProductFacets, ProductFilter,
+        ProductKey, ProductPageRequest, ProductSearchRequest, ProductSortField, Reservation,
+        SortOrder,
+    },
+    dtos::{
+        BatchGetProductsResponse, CreateProductResponse, EmptyResponse, FacetBucketResponse,
+        ListProductsResponse, ProductFacetsResponse, ProductResponse, ProductSearchResult,
+        Response, SearchProductsResponse,
+    },
+    events::{Event, OrderItem},
+    search::{highlight, snippet, tokenize},
 };
 
 // traits
@@ -17,12 +30,12 @@ pub trait Query {}
 
 #[async_trait]
 pub trait CommandHandler<C: Command, R: Response> {
-    async fn handle(&self, input: &C) -> Result<R, String>;
+    async fn handle(&self, input: &C) -> Result<R, DomainError>;
 }
 
 #[async_trait]
 pub trait QueryHandler<Q: Query, R: Response> {
-    async fn handle(&self, input: Option<Q>) -> Result<R, String>;
+    async fn handle(&self, input: Option<Q>) -> Result<R, DomainError>;
 }
 
 // commands
@@ -31,32 +44,544 @@ pub struct CreateProductCommand {
     pub name: String,
     pub price: f32,
     pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub brand: Option<String>,
+    #[serde(default)]
+    pub sku: Option<String>,
 }
 impl Command for CreateProductCommand {}
 
+#[derive(Serialize, Deserialize)]
+pub struct UpdateProductCommand {
+    #[serde(default)]
+    pub product_id: String,
+    pub name: String,
+    pub price: f32,
+    pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub brand: Option<String>,
+    #[serde(default)]
+    pub sku: Option<String>,
+    #[serde(skip)]
+    pub expected_version: Option<u32>,
+}
+impl Command for UpdateProductCommand {}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteProductCommand {
+    pub product_id: String,
+    #[serde(skip)]
+    pub expected_version: Option<u32>,
+}
+impl Command for DeleteProductCommand {}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreProductCommand {
+    pub product_id: String,
+}
+impl Command for RestoreProductCommand {}
+
+pub struct PurgeDeletedProductsCommand {
+    pub deleted_before_utc: i64,
+}
+impl Command for PurgeDeletedProductsCommand {}
+
+pub struct ReleaseExpiredReservationsCommand {
+    pub expired_before_utc: i64,
+}
+impl Command for ReleaseExpiredReservationsCommand {}
+
 #[derive(Serialize, Deserialize)]
 pub struct ModifyProductInventoryCommand {
     pub product_id: String,
     pub new_inventory: u32,
+    #[serde(skip)]
+    pub expected_version: Option<u32>,
 }
 impl Command for ModifyProductInventoryCommand {}
 
 #[derive(Serialize, Deserialize)]
 pub struct DecrementProductReservedInventoryCommand {
     pub product_id: String,
+    pub cart_id: Option<String>,
+    pub quantity: u32,
+    pub message_id: Option<String>,
 }
 impl Command for DecrementProductReservedInventoryCommand {}
 
 pub struct IncrementProdcuctReservedInventoryCommand {
     pub product_id: String,
+    pub cart_id: Option<String>,
+    pub quantity: u32,
+    pub message_id: Option<String>,
 }
 impl Command for IncrementProdcuctReservedInventoryCommand {}
 
+pub struct CommitOrderedInventoryCommand {
+    pub order_id: String,
+    pub cart_id: Option<String>,
+    pub items: Vec<OrderItem>,
+    pub message_id: Option<String>,
+}
+impl Command for CommitOrderedInventoryCommand {}
+
+pub struct RestockOrderedInventoryCommand {
+    pub order_id: String,
+    pub items: Vec<OrderItem>,
+    pub message_id: Option<String>,
+}
+impl Command for RestockOrderedInventoryCommand {}
+
 // queries
-pub struct GetProductsQuery {
-    pub id: String,
+pub struct GetProductQuery {
+    pub key: ProductKey,
+}
+impl Query for GetProductQuery {}
+
+/// Body of `POST /products:batchGet`.
+#[derive(Debug, Default, Deserialize)]
+pub struct BatchGetProductsQuery {
+    pub ids: Vec<String>,
+}
+impl Query for BatchGetProductsQuery {}
+
+/// Query string for `GET /products`; `page_token` is the `next_page_token` of the previous page.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListProductsQuery {
+    pub page_size: Option<u32>,
+    pub page_token: Option<String>,
+    pub min_price: Option<f32>,
+    pub max_price: Option<f32>,
+    #[serde(default)]
+    pub in_stock: bool,
+    pub min_stars: Option<u8>,
+    #[serde(default)]
+    pub sort_by: ProductSortField,
+    #[serde(default)]
+    pub sort_order: SortOrder,
+    /// Also count facet buckets over every matching product, not just this page.
+    #[serde(default)]
+    pub include_facets: bool,
+}
+impl Query for ListProductsQuery {}
+
+/// Query string for `GET /products/search`; accepts the same filters and paging as the listing,
+/// but results are always ordered by relevance.
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchProductsQuery {
+    #[serde(default)]
+    pub q: String,
+    pub page_size: Option<u32>,
+    pub page_token: Option<String>,
+    pub min_price: Option<f32>,
+    pub max_price: Option<f32>,
+    #[serde(default)]
+    pub in_stock: bool,
+    pub min_stars: Option<u8>,
+    #[serde(default)]
+    pub include_facets: bool,
+}
+impl Query for SearchProductsQuery {}
+
+// helpers
+fn product_slug(name: &str) -> String {
+    tokenize(name).join("-")
+}
+
+fn product_response(domain_product: Product) -> ProductResponse {
+    ProductResponse {
+        id: domain_product.id,
+        name: domain_product.name,
+        price: domain_product.price,
+        description: domain_product.description,
+        category: domain_product.category,
+        brand: domain_product.brand,
+        slug: domain_product.slug,
+        sku: domain_product.sku,
+        available_inventory: domain_product.available_inventory,
+        reserved_inventory: domain_product.reserved_inventory,
+        stars: domain_product.stars,
+        number_of_reviews: domain_product.number_of_reviews,
+        version: domain_product.version,
+    }
+}
+
+fn facet_buckets_response(buckets: Vec<FacetBucket>) -> Vec<FacetBucketResponse> {
+    buckets
+        .into_iter()
+        .map(|bucket| FacetBucketResponse {
+            value: bucket.value,
+            count: bucket.count,
+        })
+        .collect()
+}
+
+fn product_facets_response(facets: ProductFacets) -> ProductFacetsResponse {
+    ProductFacetsResponse {
+        price: facet_buckets_response(facets.price),
+        rating: facet_buckets_response(facets.rating),
+        stock: facet_buckets_response(facets.stock),
+        category: facet_buckets_response(facets.category),
+        brand: facet_buckets_response(facets.brand),
+    }
+}
+
+// Page tokens are the offset of the next page, returned as `next_page_token` by the previous page
+fn page_offset_and_limit(
+    page_size: Option<u32>,
+    page_token: Option<&str>,
+) -> Result<(u64, u32), DomainError> {
+    let limit = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(DomainError::Validation(format!(
+            "Page size must be between 1 and {}!!!",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    let offset = match page_token {
+        Some(page_token) => page_token
+            .parse()
+            .map_err(|_| DomainError::Validation(String::from("Page token is not valid!!!")))?,
+        None => 0,
+    };
+
+    Ok((offset, limit))
+}
+
+fn next_page_token(offset: u64, returned: usize, total_count: u64) -> Option<String> {
+    let next_offset = offset + returned as u64;
+    (next_offset < total_count).then(|| next_offset.to_string())
+}
+
+fn product_filter(
+    min_price: Option<f32>,
+    max_price: Option<f32>,
+    in_stock: bool,
+    min_stars: Option<u8>,
+) -> Result<ProductFilter, DomainError> {
+    if let (Some(min_price), Some(max_price)) = (min_price, max_price) {
+        if min_price > max_price {
+            return Err(DomainError::Validation(String::from(
+                "Minimum price cannot be greater than maximum price!!!",
+            )));
+        }
+    }
+
+    Ok(ProductFilter {
+        min_price,
+        max_price,
+        in_stock_only: in_stock,
+        min_stars,
+    })
+}
+
+fn product_page_request(input: &ListProductsQuery) -> Result<ProductPageRequest, DomainError> {
+    let (offset, limit) = page_offset_and_limit(input.page_size, input.page_token.as_deref())?;
+
+    Ok(ProductPageRequest {
+        filter: product_filter(
+            input.min_price,
+            input.max_price,
+            input.in_stock,
+            input.min_stars,
+        )?,
+        sort_by: input.sort_by,
+        sort_order: input.sort_order,
+        offset,
+        limit,
+    })
+}
+
+fn product_search_request(
+    input: &SearchProductsQuery,
+) -> Result<ProductSearchRequest, DomainError> {
+    if tokenize(&input.q).is_empty() {
+        return Err(DomainError::Validation(String::from(
+            "Search text cannot be empty!!!",
+        )));
+    }
+
+    let (offset, limit) = page_offset_and_limit(input.page_size, input.page_token.as_deref())?;
+
+    Ok(ProductSearchRequest {
+        text: input.q.clone(),
+        filter: product_filter(
+            input.min_price,
+            input.max_price,
+            input.in_stock,
+            input.min_stars,
+        )?,
+        offset,
+        limit,
+    })
+}
+
+fn validate_product_details(name: &str, price: f32, description: &str) -> Result<(), DomainError> {
+    if price <= 0.0 {
+        return Err(DomainError::Validation(String::from(
+            "Price cannot be 0 or negative!!!",
+        )));
+    }
+
+    if name.is_empty() {
+        return Err(DomainError::Validation(String::from(
+            "Name cannot be empty!!!",
+        )));
+    }
+
+    if description.is_empty() {
+        return Err(DomainError::Validation(String::from(
+            "Description cannot be empty!!!",
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_expected_version(
+    product: &Product,
+    expected_version: Option<u32>,
+) -> Result<(), DomainError> {
+    match expected_version {
+        Some(version) if version != product.version => Err(DomainError::Conflict(format!(
+            "Product {} is at version {} but version {} was expected",
+            product.id, product.version, version
+        ))),
+        _ => Ok(()),
+    }
+}
+
+const MAX_BATCH_GET_IDS: usize = 100;
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+const MAX_CONFLICT_RETRIES: u32 = 5;
+const CONFLICT_RETRY_BASE_DELAY_MILLIS: u64 = 20;
+
+// Callers that pinned a version through If-Match should see the conflict instead of a retry
+fn conflict_retries_for(expected_version: Option<u32>) -> u32 {
+    match expected_version {
+        Some(_) => 0,
+        None => MAX_CONFLICT_RETRIES,
+    }
+}
+
+async fn retry_on_conflict<T, F, Fut>(max_retries: u32, operation: F) -> Result<T, DomainError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, DomainError>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match operation().await {
+            Err(DomainError::Conflict(e)) if attempt < max_retries => {
+                attempt += 1;
+                event!(
+                    Level::DEBUG,
+                    "Retrying after version conflict (attempt {}): {}",
+                    attempt,
+                    e
+                );
+                tokio::time::sleep(Duration::from_millis(
+                    CONFLICT_RETRY_BASE_DELAY_MILLIS * 2u64.pow(attempt),
+                ))
+                .await;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn sellable_inventory(available_inventory: i64, reserved_inventory: i64) -> i64 {
+    (available_inventory - reserved_inventory).max(0)
+}
+
+fn inventory_changed_events(previous_sellable_inventory: i64, product: &Product) -> Vec<Event> {
+    let sellable_inventory = sellable_inventory(
+        product.available_inventory as i64,
+        product.reserved_inventory as i64,
+    );
+
+    let mut events = vec![Event::ProductInventoryChangedEvent {
+        id: product.id.clone(),
+        available_inventory: product.available_inventory,
+        reserved_inventory: product.reserved_inventory,
+    }];
+
+    if previous_sellable_inventory > 0 && sellable_inventory == 0 {
+        events.push(Event::ProductOutOfStockEvent {
+            id: product.id.clone(),
+        });
+    } else if previous_sellable_inventory == 0 && sellable_inventory > 0 {
+        events.push(Event::ProductBackInStockEvent {
+            id: product.id.clone(),
+            available_quantity: sellable_inventory as u32,
+        });
+    }
+
+    events
+}
+
+fn product_updated_events(previous_price: f32, product: &Product) -> Vec<Event> {
+    let mut events = vec![Event::ProductUpdatedEvent {
+        id: product.id.clone(),
+        name: product.name.clone(),
+        price: product.price,
+        description: product.description.clone(),
+        version: product.version,
+    }];
+
+    if previous_price != product.price {
+        events.push(Event::ProductPriceChangedEvent {
+            id: product.id.clone(),
+            previous_price,
+            price: product.price,
+        });
+    }
+
+    events
+}
+
+async fn register_events(
+    uow: &Arc<dyn UnitOfWork + Send + Sync>,
+    session: Arc<tokio::sync::Mutex<mongodb::ClientSession>>,
+    events: Vec<Event>,
+) -> Result<(), DomainError> {
+    for event in events {
+        uow.register_event(event, session.clone()).await?;
+    }
+
+    Ok(())
+}
+
+// The deltas are undone against the adjusted product to find the stock level it started from
+async fn register_inventory_events(
+    uow: &Arc<dyn UnitOfWork + Send + Sync>,
+    session: Arc<tokio::sync::Mutex<mongodb::ClientSession>>,
+    adjusted_product: &Product,
+    available_delta: i64,
+    reserved_delta: i64,
+) -> Result<(), DomainError> {
+    let previous_sellable_inventory = sellable_inventory(
+        adjusted_product.available_inventory as i64 - available_delta,
+        adjusted_product.reserved_inventory as i64 - reserved_delta,
+    );
+
+    register_events(
+        uow,
+        session,
+        inventory_changed_events(previous_sellable_inventory, adjusted_product),
+    )
+    .await
+}
+
+async fn update_in_transaction<F>(
+    uow: &Arc<dyn UnitOfWork + Send + Sync>,
+    product: Product,
+    events_for: F,
+) -> Result<Product, DomainError>
+where
+    F: Fn(&Product) -> Vec<Event> + Send + Sync,
+{
+    let product_repository = uow.get_product_repository().await;
+
+    uow.run_in_transaction(|session| async {
+        let updated_product = product_repository
+            .update(product.id.clone(), product.clone(), session.clone())
+            .await?;
+        register_events(uow, session, events_for(&updated_product)).await?;
+
+        Ok(updated_product)
+    })
+    .await
+}
+
+fn validate_order_items(items: &[OrderItem]) -> Result<(), DomainError> {
+    if items.is_empty() {
+        return Err(DomainError::Validation(String::from(
+            "Order must contain at least one item!!!",
+        )));
+    }
+
+    if items.iter().any(|item| item.quantity == 0) {
+        return Err(DomainError::Validation(String::from(
+            "Quantity must be greater than 0!!!",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Records the consumed message as processed in the open transaction. Returns `false` when
+/// the message was already processed, so redeliveries have no side effects.
+async fn claim_message(
+    uow: &Arc<dyn UnitOfWork + Send + Sync>,
+    message_id: Option<&str>,
+    session: Arc<tokio::sync::Mutex<mongodb::ClientSession>>,
+) -> Result<bool, DomainError> {
+    let message_id = match message_id {
+        Some(message_id) => message_id,
+        None => return Ok(true),
+    };
+
+    let processed_message = ProcessedMessage {
+        id: message_id.to_string(),
+        processed_at_utc: current_utc_millis(),
+    };
+
+    let claimed = uow
+        .get_processed_message_repository()
+        .await
+        .insert_if_absent(processed_message, session)
+        .await?;
+
+    if !claimed {
+        event!(
+            Level::INFO,
+            "Skipping already processed message {}",
+            message_id
+        );
+    }
+
+    Ok(claimed)
+}
+
+/// Returns `None` when `message_id` had already been processed and nothing was adjusted.
+async fn adjust_inventory_in_transaction(
+    uow: &Arc<dyn UnitOfWork + Send + Sync>,
+    product_id: &str,
+    available_delta: i64,
+    reserved_delta: i64,
+    message_id: Option<&str>,
+) -> Result<Option<Product>, DomainError> {
+    let product_repository = uow.get_product_repository().await;
+
+    uow.run_in_transaction(|session| async {
+        if !claim_message(uow, message_id, session.clone()).await? {
+            return Ok(None);
+        }
+
+        let adjusted_product = product_repository
+            .adjust_inventory(product_id, available_delta, reserved_delta, session.clone())
+            .await?;
+        register_inventory_events(
+            uow,
+            session,
+            &adjusted_product,
+            available_delta,
+            reserved_delta,
+        )
+        .await?;
+
+        Ok(Some(adjusted_product))
+    })
+    .await
 }
-impl Query for GetProductsQuery {}
 
 // command handlers
 #[derive(Clone)]
@@ -66,232 +591,210 @@ pub struct CreateProductCommandHandler {
 
 impl CreateProductCommandHandler {
     pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
-        CreateProductCommandHandler { uow: uow }
+        CreateProductCommandHandler { uow }
     }
 }
 
 #[async_trait]
 impl CommandHandler<CreateProductCommand, CreateProductResponse> for CreateProductCommandHandler {
-    async fn handle(&self, input: &CreateProductCommand) -> Result<CreateProductResponse, String> {
-        if input.price <= 0.0 {
-            return Err(String::from("Price cannot be 0 or negative!!!"));
-        }
-
-        if input.name.is_empty() {
-            return Err(String::from("Name cannot be empty!!!"));
-        }
-
-        if input.description.is_empty() {
-            return Err(String::from("Description cannot be empty!!!"));
-        }
+    async fn handle(
+        &self,
+        input: &CreateProductCommand,
+    ) -> Result<CreateProductResponse, DomainError> {
+        validate_product_details(&input.name, input.price, &input.description)?;
 
-        let since_the_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("oops")
-            .as_millis();
+        let since_the_epoch = current_utc_millis();
 
         let domain_product = Product {
             id: uuid::Uuid::new_v4().to_string(),
             name: input.name.clone(),
             description: input.description.clone(),
+            category: input.category.clone(),
+            brand: input.brand.clone(),
+            slug: product_slug(&input.name),
+            sku: input.sku.clone(),
             price: input.price,
             available_inventory: 0,
             reserved_inventory: 0,
             stars: 0,
             number_of_reviews: 0,
-            created_at_utc: since_the_epoch as i64,
-            updated_at_utc: since_the_epoch as i64,
+            created_at_utc: since_the_epoch,
+            updated_at_utc: since_the_epoch,
             version: 0,
+            deleted_at_utc: None,
         };
 
         let product_repository = self.uow.get_product_repository().await;
-        let session = self.uow.begin_transaction().await;
 
-        match product_repository
-            .create(domain_product.id.clone(), domain_product, session)
+        let product_created_event = Event::ProductCreatedEvent {
+            id: domain_product.id.clone(),
+            name: domain_product.name.clone(),
+            price: domain_product.price,
+        };
+
+        match self
+            .uow
+            .run_in_transaction(|session| async {
+                let created_product = product_repository
+                    .create(
+                        domain_product.id.clone(),
+                        domain_product.clone(),
+                        session.clone(),
+                    )
+                    .await?;
+                self.uow
+                    .register_event(product_created_event.clone(), session)
+                    .await?;
+
+                Ok(created_product)
+            })
             .await
         {
-            Ok(created_product) => match self.uow.commit().await {
-                Ok(()) => Ok(CreateProductResponse {
-                    id: created_product.id.clone(),
-                }),
-                Err(e) => {
-                    event!(Level::WARN, "Error occurred while adding product: {}", e);
-                    Err(e)
-                }
-            },
+            Ok(created_product) => Ok(CreateProductResponse {
+                id: created_product.id.clone(),
+            }),
             Err(e) => {
                 event!(Level::WARN, "Error occurred while adding product: {}", e);
-                self.uow.rollback().await.unwrap();
                 Err(e)
             }
         }
     }
 }
 
-// query handlers
 #[derive(Clone)]
-pub struct GetProductsQueryHandler {
+pub struct UpdateProductCommandHandler {
     uow: Arc<dyn UnitOfWork + Send + Sync>,
 }
 
-impl GetProductsQueryHandler {
+impl UpdateProductCommandHandler {
     pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
-        GetProductsQueryHandler { uow: uow }
+        UpdateProductCommandHandler { uow }
+    }
+
+    async fn try_update(&self, input: &UpdateProductCommand) -> Result<Product, DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+
+        let mut found_product = product_repository.read(&input.product_id).await?;
+        check_expected_version(&found_product, input.expected_version)?;
+
+        let previous_price = found_product.price;
+        found_product.name = input.name.clone();
+        found_product.price = input.price;
+        found_product.description = input.description.clone();
+        found_product.category = input.category.clone();
+        found_product.brand = input.brand.clone();
+        found_product.slug = product_slug(&input.name);
+        found_product.sku = input.sku.clone();
+        found_product.updated_at_utc = current_utc_millis();
+
+        update_in_transaction(&self.uow, found_product, |updated_product| {
+            product_updated_events(previous_price, updated_product)
+        })
+        .await
     }
 }
 
 #[async_trait]
-impl QueryHandler<GetProductsQuery, GetProductsResponse> for GetProductsQueryHandler {
-    async fn handle(
-        &self,
-        input_option: Option<GetProductsQuery>,
-    ) -> Result<GetProductsResponse, String> {
-        let product_repository = self.uow.get_product_repository().await;
-        match input_option {
-            Some(input) => match product_repository.read(input.id.as_str()).await {
-                Ok(domain_product) => {
-                    let mut products = Vec::new();
-
-                    products.push(ProductResponse {
-                        id: domain_product.id.clone(),
-                        name: domain_product.name.clone(),
-                        price: domain_product.price,
-                        description: domain_product.description.clone(),
-                        available_inventory: domain_product.available_inventory,
-                        reserved_inventory: domain_product.reserved_inventory,
-                        stars: domain_product.stars,
-                        number_of_reviews: domain_product.number_of_reviews,
-                    });
-
-                    Ok(GetProductsResponse { products: products })
-                }
-                Err(e) => {
-                    event!(Level::WARN, "Error occurred while adding product: {}", e);
-                    Err(e)
-                }
-            },
-            None => match product_repository.read_all().await {
-                Ok(domain_products) => {
-                    let mut products = Vec::new();
-
-                    for domain_product in domain_products {
-                        products.push(ProductResponse {
-                            id: domain_product.id.clone(),
-                            name: domain_product.name.clone(),
-                            price: domain_product.price,
-                            description: domain_product.description.clone(),
-                            available_inventory: domain_product.available_inventory,
-                            reserved_inventory: domain_product.reserved_inventory,
-                            stars: domain_product.stars,
-                            number_of_reviews: domain_product.number_of_reviews,
-                        });
-                    }
+impl CommandHandler<UpdateProductCommand, EmptyResponse> for UpdateProductCommandHandler {
+    async fn handle(&self, input: &UpdateProductCommand) -> Result<EmptyResponse, DomainError> {
+        validate_product_details(&input.name, input.price, &input.description)?;
 
-                    Ok(GetProductsResponse { products: products })
-                }
-                Err(e) => {
-                    event!(Level::WARN, "Error occurred while adding product: {}", e);
-                    Err(e)
-                }
-            },
+        match retry_on_conflict(conflict_retries_for(input.expected_version), || {
+            self.try_update(input)
+        })
+        .await
+        {
+            Ok(_) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while updating product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e)
+            }
         }
     }
 }
 
-pub struct ModifyProductInventoryCommandHandler {
+#[derive(Clone)]
+pub struct DeleteProductCommandHandler {
     uow: Arc<dyn UnitOfWork + Send + Sync>,
 }
 
-impl ModifyProductInventoryCommandHandler {
+impl DeleteProductCommandHandler {
     pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
-        ModifyProductInventoryCommandHandler { uow: uow }
+        DeleteProductCommandHandler { uow }
     }
-}
 
-#[async_trait]
-impl CommandHandler<ModifyProductInventoryCommand, EmptyResponse>
-    for ModifyProductInventoryCommandHandler
-{
-    async fn handle(&self, input: &ModifyProductInventoryCommand) -> Result<EmptyResponse, String> {
+    async fn try_delete(&self, input: &DeleteProductCommand) -> Result<Product, DomainError> {
         let product_repository = self.uow.get_product_repository().await;
 
-        match product_repository.read(&input.product_id).await {
-            Ok(mut found_product) => {
-                found_product.available_inventory = input.new_inventory;
+        let mut found_product = product_repository.read(&input.product_id).await?;
+        check_expected_version(&found_product, input.expected_version)?;
 
-                let session = self.uow.begin_transaction().await;
+        let since_the_epoch = current_utc_millis();
+        found_product.deleted_at_utc = Some(since_the_epoch);
+        found_product.updated_at_utc = since_the_epoch;
 
-                match product_repository
-                    .update(input.product_id.clone(), found_product, session)
-                    .await
-                {
-                    Ok(_) => {
-                        self.uow.commit().await.unwrap();
-                        Ok(EmptyResponse {})
-                    }
-                    Err(e) => {
-                        self.uow.rollback().await.unwrap();
-                        Err(format!(
-                            "Error occurred while modifying product inventory for product {}: {}",
-                            &input.product_id, e
-                        ))
-                    }
-                }
+        update_in_transaction(&self.uow, found_product, |_| Vec::new()).await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<DeleteProductCommand, EmptyResponse> for DeleteProductCommandHandler {
+    async fn handle(&self, input: &DeleteProductCommand) -> Result<EmptyResponse, DomainError> {
+        match retry_on_conflict(conflict_retries_for(input.expected_version), || {
+            self.try_delete(input)
+        })
+        .await
+        {
+            Ok(_) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while deleting product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e)
             }
-            Err(e) => Err(format!(
-                "Error occurred while modifying product inventory for product {}: {}",
-                &input.product_id, e
-            )),
         }
     }
 }
 
-pub struct DecrementProductInventoryCommandHandler {
+#[derive(Clone)]
+pub struct RestoreProductCommandHandler {
     uow: Arc<dyn UnitOfWork + Send + Sync>,
 }
 
-impl DecrementProductInventoryCommandHandler {
+impl RestoreProductCommandHandler {
     pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
-        DecrementProductInventoryCommandHandler { uow: uow }
+        RestoreProductCommandHandler { uow }
     }
-}
 
-#[async_trait]
-impl CommandHandler<DecrementProductReservedInventoryCommand, EmptyResponse>
-    for DecrementProductInventoryCommandHandler
-{
-    async fn handle(
-        &self,
-        input: &DecrementProductReservedInventoryCommand,
-    ) -> Result<EmptyResponse, String> {
+    async fn try_restore(&self, input: &RestoreProductCommand) -> Result<Product, DomainError> {
         let product_repository = self.uow.get_product_repository().await;
 
-        match product_repository.read(&input.product_id.as_str()).await {
-            Ok(mut domain_product) => {
-                domain_product.reserved_inventory = domain_product.reserved_inventory - 1;
+        let mut found_product = product_repository.read_deleted(&input.product_id).await?;
 
-                let session = self.uow.begin_transaction().await;
+        found_product.deleted_at_utc = None;
+        found_product.updated_at_utc = current_utc_millis();
 
-                match product_repository
-                    .update(domain_product.id.clone(), domain_product, session)
-                    .await
-                {
-                    Ok(_) => {
-                        self.uow.commit().await.unwrap();
-                        Ok(EmptyResponse {})
-                    }
-                    Err(e) => {
-                        self.uow.rollback().await.unwrap();
-                        event!(Level::WARN, "Error occurred while updating product while decrementing inventory for product {}: {}", input.product_id, e);
-                        Err(e)
-                    }
-                }
-            }
+        update_in_transaction(&self.uow, found_product, |_| Vec::new()).await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<RestoreProductCommand, EmptyResponse> for RestoreProductCommandHandler {
+    async fn handle(&self, input: &RestoreProductCommand) -> Result<EmptyResponse, DomainError> {
+        match retry_on_conflict(MAX_CONFLICT_RETRIES, || self.try_restore(input)).await {
+            Ok(_) => Ok(EmptyResponse {}),
             Err(e) => {
                 event!(
                     Level::WARN,
-                    "Error occurred while decrementing product inventory for product {}: {}",
+                    "Error occurred while restoring product {}: {}",
                     input.product_id,
                     e
                 );
@@ -301,52 +804,46 @@ impl CommandHandler<DecrementProductReservedInventoryCommand, EmptyResponse>
     }
 }
 
-pub struct IncrementProdcuctInventoryCommandHandler {
+#[derive(Clone)]
+pub struct PurgeDeletedProductsCommandHandler {
     uow: Arc<dyn UnitOfWork + Send + Sync>,
 }
 
-impl IncrementProdcuctInventoryCommandHandler {
+impl PurgeDeletedProductsCommandHandler {
     pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
-        IncrementProdcuctInventoryCommandHandler { uow: uow }
+        PurgeDeletedProductsCommandHandler { uow }
     }
 }
 
 #[async_trait]
-impl CommandHandler<IncrementProdcuctReservedInventoryCommand, EmptyResponse>
-    for IncrementProdcuctInventoryCommandHandler
+impl CommandHandler<PurgeDeletedProductsCommand, EmptyResponse>
+    for PurgeDeletedProductsCommandHandler
 {
     async fn handle(
         &self,
-        input: &IncrementProdcuctReservedInventoryCommand,
-    ) -> Result<EmptyResponse, String> {
+        input: &PurgeDeletedProductsCommand,
+    ) -> Result<EmptyResponse, DomainError> {
         let product_repository = self.uow.get_product_repository().await;
+        let product_repository = &product_repository;
 
-        match product_repository.read(&input.product_id.as_str()).await {
-            Ok(mut domain_product) => {
-                domain_product.reserved_inventory = domain_product.reserved_inventory + 1;
-
-                let session = self.uow.begin_transaction().await;
-
-                match product_repository
-                    .update(domain_product.id.clone(), domain_product, session)
+        match self
+            .uow
+            .run_in_transaction(|session| async move {
+                product_repository
+                    .delete_all_deleted_before(input.deleted_before_utc, session)
                     .await
-                {
-                    Ok(_) => {
-                        self.uow.commit().await.unwrap();
-                        Ok(EmptyResponse {})
-                    }
-                    Err(e) => {
-                        self.uow.rollback().await.unwrap();
-                        event!(Level::WARN, "Error occurred while updating product while incrementing inventory for product {}: {}", input.product_id, e);
-                        Err(e)
-                    }
-                }
+            })
+            .await
+        {
+            Ok(0) => Ok(EmptyResponse {}),
+            Ok(purged_count) => {
+                event!(Level::INFO, "Purged {} deleted products", purged_count);
+                Ok(EmptyResponse {})
             }
             Err(e) => {
                 event!(
                     Level::WARN,
-                    "Error occurred while incrementing product inventory for product {}: {}",
-                    input.product_id,
+                    "Error occurred while purging deleted products: {}",
                     e
                 );
                 Err(e)
@@ -355,28 +852,1628 @@ impl CommandHandler<IncrementProdcuctReservedInventoryCommand, EmptyResponse>
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::uow::MockUnitOfWork;
-
-    use super::*;
-
-    #[tokio::test]
-    async fn create_product_command_handler_returns_err_when_price_is_negative() {
-        // Arrange
-        let create_product_command: CreateProductCommand = CreateProductCommand {
-            name: String::from("laptop"),
-            price: -1.0,
-            description: String::from("desc"),
-        };
+#[derive(Clone)]
+pub struct GetProductQueryHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
 
-        let handler: CreateProductCommandHandler =
-            CreateProductCommandHandler::new(Arc::new(MockUnitOfWork::new()));
+impl GetProductQueryHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        GetProductQueryHandler { uow }
+    }
+}
 
-        // Act
-        let result = handler.handle(&create_product_command).await;
+#[async_trait]
+impl QueryHandler<GetProductQuery, ProductResponse> for GetProductQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetProductQuery>,
+    ) -> Result<ProductResponse, DomainError> {
+        let input = input_option.ok_or_else(|| {
+            DomainError::Validation(String::from("A product id, slug or SKU is required!!!"))
+        })?;
 
-        // Assert
+        let product_repository = self.uow.get_product_repository().await;
+        match product_repository.read_by_key(&input.key).await {
+            Ok(domain_product) => Ok(product_response(domain_product)),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while reading product: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BatchGetProductsQueryHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl BatchGetProductsQueryHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        BatchGetProductsQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<BatchGetProductsQuery, BatchGetProductsResponse>
+    for BatchGetProductsQueryHandler
+{
+    async fn handle(
+        &self,
+        input_option: Option<BatchGetProductsQuery>,
+    ) -> Result<BatchGetProductsResponse, DomainError> {
+        let input = input_option.unwrap_or_default();
+
+        // Duplicates are collapsed, keeping the order the ids were first requested in
+        let mut ids: Vec<String> = Vec::with_capacity(input.ids.len());
+        for id in input.ids {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        if ids.is_empty() || ids.len() > MAX_BATCH_GET_IDS {
+            return Err(DomainError::Validation(format!(
+                "Between 1 and {} product ids are required!!!",
+                MAX_BATCH_GET_IDS
+            )));
+        }
+
+        let product_repository = self.uow.get_product_repository().await;
+        match product_repository.read_many(&ids).await {
+            Ok(domain_products) => {
+                let mut products_by_id: HashMap<String, Product> = domain_products
+                    .into_iter()
+                    .map(|product| (product.id.clone(), product))
+                    .collect();
+
+                let mut products = Vec::with_capacity(products_by_id.len());
+                let mut not_found_ids = Vec::new();
+                for id in ids {
+                    match products_by_id.remove(&id) {
+                        Some(product) => products.push(product_response(product)),
+                        None => not_found_ids.push(id),
+                    }
+                }
+
+                Ok(BatchGetProductsResponse {
+                    products,
+                    not_found_ids,
+                })
+            }
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while reading products: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct ListProductsQueryHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl ListProductsQueryHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        ListProductsQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<ListProductsQuery, ListProductsResponse> for ListProductsQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<ListProductsQuery>,
+    ) -> Result<ListProductsResponse, DomainError> {
+        let input = input_option.unwrap_or_default();
+        let page_request = product_page_request(&input)?;
+
+        let product_repository = self.uow.get_product_repository().await;
+        let result = async {
+            let page = product_repository.read_page(&page_request).await?;
+            let facets = match input.include_facets {
+                true => Some(
+                    product_repository
+                        .facets(&page_request.filter, None)
+                        .await?,
+                ),
+                false => None,
+            };
+
+            Ok::<_, DomainError>((page, facets))
+        }
+        .await;
+
+        match result {
+            Ok((page, facets)) => Ok(ListProductsResponse {
+                next_page_token: next_page_token(
+                    page_request.offset,
+                    page.products.len(),
+                    page.total_count,
+                ),
+                products: page.products.into_iter().map(product_response).collect(),
+                total_count: page.total_count,
+                facets: facets.map(product_facets_response),
+            }),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while listing products: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct SearchProductsQueryHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl SearchProductsQueryHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        SearchProductsQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<SearchProductsQuery, SearchProductsResponse> for SearchProductsQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<SearchProductsQuery>,
+    ) -> Result<SearchProductsResponse, DomainError> {
+        let input = input_option.unwrap_or_default();
+        let search_request = product_search_request(&input)?;
+        let terms = tokenize(&search_request.text);
+
+        let product_repository = self.uow.get_product_repository().await;
+        let result = async {
+            let page = product_repository.search(&search_request).await?;
+            let facets = match input.include_facets {
+                true => Some(
+                    product_repository
+                        .facets(&search_request.filter, Some(&search_request.text))
+                        .await?,
+                ),
+                false => None,
+            };
+
+            Ok::<_, DomainError>((page, facets))
+        }
+        .await;
+
+        match result {
+            Ok((page, facets)) => Ok(SearchProductsResponse {
+                next_page_token: next_page_token(
+                    search_request.offset,
+                    page.hits.len(),
+                    page.total_count,
+                ),
+                results: page
+                    .hits
+                    .into_iter()
+                    .map(|hit| ProductSearchResult {
+                        highlighted_name: highlight(&hit.product.name, &terms),
+                        snippet: snippet(&hit.product.description, &terms),
+                        score: hit.score,
+                        product: product_response(hit.product),
+                    })
+                    .collect(),
+                total_count: page.total_count,
+                facets: facets.map(product_facets_response),
+            }),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while searching products: {}",
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct ModifyProductInventoryCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl ModifyProductInventoryCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        ModifyProductInventoryCommandHandler { uow }
+    }
+
+    async fn try_modify(
+        &self,
+        input: &ModifyProductInventoryCommand,
+    ) -> Result<Product, DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+
+        let mut found_product = product_repository.read(&input.product_id).await?;
+        check_expected_version(&found_product, input.expected_version)?;
+        // The versioned update turns a reservation landing after this check into a conflict
+        if input.new_inventory < found_product.reserved_inventory {
+            return Err(DomainError::InsufficientStock(format!(
+                "Inventory for product with id {} cannot be set below the {} reserved units",
+                input.product_id, found_product.reserved_inventory
+            )));
+        }
+
+        let previous_sellable_inventory = sellable_inventory(
+            found_product.available_inventory as i64,
+            found_product.reserved_inventory as i64,
+        );
+        found_product.available_inventory = input.new_inventory;
+        found_product.updated_at_utc = current_utc_millis();
+
+        update_in_transaction(&self.uow, found_product, |updated_product| {
+            inventory_changed_events(previous_sellable_inventory, updated_product)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ModifyProductInventoryCommand, EmptyResponse>
+    for ModifyProductInventoryCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &ModifyProductInventoryCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        match retry_on_conflict(conflict_retries_for(input.expected_version), || {
+            self.try_modify(input)
+        })
+        .await
+        {
+            Ok(_) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while modifying product inventory for product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct DecrementProductInventoryCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl DecrementProductInventoryCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        DecrementProductInventoryCommandHandler { uow }
+    }
+
+    async fn release_for_cart(
+        &self,
+        cart_id: &str,
+        product_id: &str,
+        message_id: Option<&str>,
+    ) -> Result<(), DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+        let reservation_repository = self.uow.get_reservation_repository().await;
+
+        // The reservation is read again on every attempt so a retried transaction never
+        // releases a stale quantity
+        self.uow
+            .run_in_transaction(|session| async {
+                let existing_reservation = match reservation_repository
+                    .read(cart_id, product_id, session.clone())
+                    .await?
+                {
+                    Some(reservation) => reservation,
+                    None => {
+                        event!(
+                            Level::DEBUG,
+                            "No reservation for product {} in cart {} to release",
+                            product_id,
+                            cart_id
+                        );
+                        return Ok(());
+                    }
+                };
+
+                if !claim_message(&self.uow, message_id, session.clone()).await? {
+                    return Ok(());
+                }
+
+                let reserved_delta = -(existing_reservation.quantity as i64);
+                let adjusted_product = product_repository
+                    .adjust_inventory(product_id, 0, reserved_delta, session.clone())
+                    .await?;
+                register_inventory_events(
+                    &self.uow,
+                    session.clone(),
+                    &adjusted_product,
+                    0,
+                    reserved_delta,
+                )
+                .await?;
+
+                reservation_repository
+                    .delete(cart_id, product_id, session)
+                    .await
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<DecrementProductReservedInventoryCommand, EmptyResponse>
+    for DecrementProductInventoryCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &DecrementProductReservedInventoryCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        let result = match &input.cart_id {
+            Some(cart_id) => {
+                self.release_for_cart(cart_id, &input.product_id, input.message_id.as_deref())
+                    .await
+            }
+            None => adjust_inventory_in_transaction(
+                &self.uow,
+                &input.product_id,
+                0,
+                -(input.quantity as i64),
+                input.message_id.as_deref(),
+            )
+            .await
+            .map(|_| ()),
+        };
+
+        match result {
+            Ok(()) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while decrementing product inventory for product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct IncrementProdcuctInventoryCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+    reservation_ttl: Duration,
+}
+
+impl IncrementProdcuctInventoryCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>, reservation_ttl: Duration) -> Self {
+        IncrementProdcuctInventoryCommandHandler {
+            uow,
+            reservation_ttl,
+        }
+    }
+
+    // A repeated request for the same cart and product only reserves the difference,
+    // but always pushes the reservation's expiry out again
+    async fn reserve_for_cart(
+        &self,
+        cart_id: &str,
+        product_id: &str,
+        quantity: u32,
+        message_id: Option<&str>,
+    ) -> Result<(), DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+        let reservation_repository = self.uow.get_reservation_repository().await;
+
+        self.uow
+            .run_in_transaction(|session| async {
+                let existing_reservation = reservation_repository
+                    .read(cart_id, product_id, session.clone())
+                    .await?;
+
+                let reserved_delta = quantity as i64
+                    - existing_reservation
+                        .as_ref()
+                        .map_or(0, |reservation| reservation.quantity as i64);
+
+                let since_the_epoch = current_utc_millis();
+                let reservation = Reservation {
+                    id: existing_reservation
+                        .as_ref()
+                        .map_or_else(|| uuid::Uuid::new_v4().to_string(), |r| r.id.clone()),
+                    cart_id: cart_id.to_string(),
+                    product_id: product_id.to_string(),
+                    quantity,
+                    created_at_utc: existing_reservation
+                        .as_ref()
+                        .map_or(since_the_epoch, |r| r.created_at_utc),
+                    updated_at_utc: since_the_epoch,
+                    expires_at_utc: since_the_epoch + self.reservation_ttl.as_millis() as i64,
+                };
+
+                if !claim_message(&self.uow, message_id, session.clone()).await? {
+                    return Ok(());
+                }
+
+                if reserved_delta != 0 {
+                    let adjusted_product = product_repository
+                        .adjust_inventory(product_id, 0, reserved_delta, session.clone())
+                        .await?;
+                    register_inventory_events(
+                        &self.uow,
+                        session.clone(),
+                        &adjusted_product,
+                        0,
+                        reserved_delta,
+                    )
+                    .await?;
+                }
+
+                reservation_repository
+                    .upsert(reservation, session)
+                    .await
+                    .map(|_| ())
+            })
+            .await
+    }
+
+    // The reservation's transaction has already rolled back, so the event goes through the
+    // outbox in a short transaction of its own
+    async fn publish_insufficient_stock(&self, input: &IncrementProdcuctReservedInventoryCommand) {
+        let product_repository = self.uow.get_product_repository().await;
+        let available_quantity = match product_repository.read(&input.product_id).await {
+            Ok(product) => product
+                .available_inventory
+                .saturating_sub(product.reserved_inventory),
+            Err(_) => 0,
+        };
+
+        let insufficient_stock_event = Event::InsufficientStockEvent {
+            product_id: input.product_id.clone(),
+            cart_id: input.cart_id.clone(),
+            requested_quantity: input.quantity,
+            available_quantity,
+        };
+
+        if let Err(e) = self
+            .uow
+            .run_in_transaction(|session| {
+                self.uow
+                    .register_event(insufficient_stock_event.clone(), session)
+            })
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Failed to publish insufficient stock event for product {}: {}",
+                input.product_id,
+                e
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<IncrementProdcuctReservedInventoryCommand, EmptyResponse>
+    for IncrementProdcuctInventoryCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &IncrementProdcuctReservedInventoryCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        if input.quantity == 0 {
+            return Err(DomainError::Validation(String::from(
+                "Quantity must be greater than 0!!!",
+            )));
+        }
+
+        let result = match &input.cart_id {
+            Some(cart_id) => {
+                self.reserve_for_cart(
+                    cart_id,
+                    &input.product_id,
+                    input.quantity,
+                    input.message_id.as_deref(),
+                )
+                .await
+            }
+            None => adjust_inventory_in_transaction(
+                &self.uow,
+                &input.product_id,
+                0,
+                input.quantity as i64,
+                input.message_id.as_deref(),
+            )
+            .await
+            .map(|_| ()),
+        };
+
+        match result {
+            Ok(()) => Ok(EmptyResponse {}),
+            Err(e) => {
+                if let DomainError::InsufficientStock(_) = e {
+                    self.publish_insufficient_stock(input).await;
+                }
+
+                event!(
+                    Level::WARN,
+                    "Error occurred while incrementing product inventory for product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct CommitOrderedInventoryCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl CommitOrderedInventoryCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        CommitOrderedInventoryCommandHandler { uow }
+    }
+
+    // Placing an order consumes the cart's reservation, so the reserved units leave the
+    // warehouse together with the available ones. Orders without a cart never reserved
+    // anything, so only the available units go.
+    async fn commit_item(
+        &self,
+        cart_id: Option<&str>,
+        item: &OrderItem,
+        session: Arc<tokio::sync::Mutex<mongodb::ClientSession>>,
+    ) -> Result<(), DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+        let reservation_repository = self.uow.get_reservation_repository().await;
+
+        let reserved_quantity = match cart_id {
+            Some(cart_id) => {
+                match reservation_repository
+                    .read(cart_id, &item.product_id, session.clone())
+                    .await?
+                {
+                    Some(reservation) => {
+                        reservation_repository
+                            .delete(cart_id, &item.product_id, session.clone())
+                            .await?;
+                        reservation.quantity
+                    }
+                    None => 0,
+                }
+            }
+            None => 0,
+        };
+
+        let available_delta = -(item.quantity as i64);
+        let reserved_delta = -(reserved_quantity as i64);
+        let adjusted_product = product_repository
+            .adjust_inventory(
+                &item.product_id,
+                available_delta,
+                reserved_delta,
+                session.clone(),
+            )
+            .await?;
+
+        register_inventory_events(
+            &self.uow,
+            session,
+            &adjusted_product,
+            available_delta,
+            reserved_delta,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<CommitOrderedInventoryCommand, EmptyResponse>
+    for CommitOrderedInventoryCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &CommitOrderedInventoryCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        validate_order_items(&input.items)?;
+
+        let result = self
+            .uow
+            .run_in_transaction(|session| async move {
+                if !claim_message(&self.uow, input.message_id.as_deref(), session.clone()).await? {
+                    return Ok(());
+                }
+
+                for item in &input.items {
+                    self.commit_item(input.cart_id.as_deref(), item, session.clone())
+                        .await?;
+                }
+
+                Ok(())
+            })
+            .await;
+
+        match result {
+            Ok(()) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while committing inventory for order {}: {}",
+                    input.order_id,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct RestockOrderedInventoryCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl RestockOrderedInventoryCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        RestockOrderedInventoryCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<RestockOrderedInventoryCommand, EmptyResponse>
+    for RestockOrderedInventoryCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &RestockOrderedInventoryCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        validate_order_items(&input.items)?;
+
+        let result = self
+            .uow
+            .run_in_transaction(|session| async move {
+                if !claim_message(&self.uow, input.message_id.as_deref(), session.clone()).await? {
+                    return Ok(());
+                }
+
+                let product_repository = self.uow.get_product_repository().await;
+
+                for item in &input.items {
+                    let available_delta = item.quantity as i64;
+                    let adjusted_product = product_repository
+                        .adjust_inventory(&item.product_id, available_delta, 0, session.clone())
+                        .await?;
+                    register_inventory_events(
+                        &self.uow,
+                        session.clone(),
+                        &adjusted_product,
+                        available_delta,
+                        0,
+                    )
+                    .await?;
+                }
+
+                Ok(())
+            })
+            .await;
+
+        match result {
+            Ok(()) => Ok(EmptyResponse {}),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while restocking inventory for order {}: {}",
+                    input.order_id,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct ReleaseExpiredReservationsCommandHandler {
+    uow: Arc<dyn UnitOfWork + Send + Sync>,
+}
+
+impl ReleaseExpiredReservationsCommandHandler {
+    pub fn new(uow: Arc<dyn UnitOfWork + Send + Sync>) -> Self {
+        ReleaseExpiredReservationsCommandHandler { uow }
+    }
+
+    async fn release(
+        &self,
+        reservation: &Reservation,
+        expired_before_utc: i64,
+    ) -> Result<Option<Reservation>, DomainError> {
+        let product_repository = self.uow.get_product_repository().await;
+        let reservation_repository = self.uow.get_reservation_repository().await;
+
+        self.uow
+            .run_in_transaction(|session| async {
+                let expired_reservation = match reservation_repository
+                    .delete_if_expired(
+                        &reservation.cart_id,
+                        &reservation.product_id,
+                        expired_before_utc,
+                        session.clone(),
+                    )
+                    .await?
+                {
+                    Some(expired_reservation) => expired_reservation,
+                    None => return Ok(None),
+                };
+
+                let reserved_delta = -(expired_reservation.quantity as i64);
+                let adjusted_product = match product_repository
+                    .adjust_inventory(
+                        &expired_reservation.product_id,
+                        0,
+                        reserved_delta,
+                        session.clone(),
+                    )
+                    .await
+                {
+                    Ok(adjusted_product) => Some(adjusted_product),
+                    // A deleted or missing product has no inventory left to hand back
+                    Err(DomainError::NotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+
+                let mut events = vec![Event::ReservationExpiredEvent {
+                    reservation_id: expired_reservation.id.clone(),
+                    cart_id: expired_reservation.cart_id.clone(),
+                    product_id: expired_reservation.product_id.clone(),
+                    quantity: expired_reservation.quantity,
+                }];
+                if let Some(adjusted_product) = adjusted_product {
+                    events.extend(inventory_changed_events(
+                        sellable_inventory(
+                            adjusted_product.available_inventory as i64,
+                            adjusted_product.reserved_inventory as i64 - reserved_delta,
+                        ),
+                        &adjusted_product,
+                    ));
+                }
+                register_events(&self.uow, session, events).await?;
+
+                Ok(Some(expired_reservation))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ReleaseExpiredReservationsCommand, EmptyResponse>
+    for ReleaseExpiredReservationsCommandHandler
+{
+    async fn handle(
+        &self,
+        input: &ReleaseExpiredReservationsCommand,
+    ) -> Result<EmptyResponse, DomainError> {
+        let reservation_repository = self.uow.get_reservation_repository().await;
+
+        let expired_reservations = match reservation_repository
+            .read_all_expired_before(input.expired_before_utc)
+            .await
+        {
+            Ok(reservations) => reservations,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while reading expired reservations: {}",
+                    e
+                );
+                return Err(e);
+            }
+        };
+
+        // One failing reservation should not keep the others holding stock
+        for reservation in expired_reservations.iter() {
+            match self.release(reservation, input.expired_before_utc).await {
+                Ok(Some(released_reservation)) => event!(
+                    Level::DEBUG,
+                    "Released expired reservation {}",
+                    released_reservation.id
+                ),
+                Ok(None) => (),
+                Err(e) => event!(
+                    Level::WARN,
+                    "Error occurred while releasing expired reservation {}: {}",
+                    reservation.id,
+                    e
+                ),
+            }
+        }
+
+        Ok(EmptyResponse {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repositories::{ProductRepository, ReservationRepository};
+    use crate::test_support::{client_session, product, InMemoryRepositories};
+    use crate::uow::MockUnitOfWork;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn create_product_command_handler_returns_err_when_price_is_negative() {
+        // Arrange
+        let create_product_command: CreateProductCommand = CreateProductCommand {
+            name: String::from("laptop"),
+            price: -1.0,
+            description: String::from("desc"),
+            category: None,
+            brand: None,
+            sku: None,
+        };
+
+        let handler: CreateProductCommandHandler =
+            CreateProductCommandHandler::new(Arc::new(MockUnitOfWork::new()));
+
+        // Act
+        let result = handler.handle(&create_product_command).await;
+
+        // Assert
+        assert!(result.is_err())
+    }
+
+    #[tokio::test]
+    async fn update_product_command_handler_returns_err_when_name_is_empty() {
+        // Arrange
+        let update_product_command: UpdateProductCommand = UpdateProductCommand {
+            product_id: String::from("1"),
+            name: String::new(),
+            price: 10.0,
+            description: String::from("desc"),
+            category: None,
+            brand: None,
+            sku: None,
+            expected_version: None,
+        };
+
+        let handler: UpdateProductCommandHandler =
+            UpdateProductCommandHandler::new(Arc::new(MockUnitOfWork::new()));
+
+        // Act
+        let result = handler.handle(&update_product_command).await;
+
+        // Assert
         assert!(result.is_err())
     }
+
+    #[tokio::test]
+    async fn retry_on_conflict_retries_until_operation_succeeds() {
+        // Arrange
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        // Act
+        let result = retry_on_conflict(MAX_CONFLICT_RETRIES, || async {
+            match attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => Err(DomainError::Conflict(String::from("conflict"))),
+                _ => Ok(()),
+            }
+        })
+        .await;
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_on_conflict_returns_conflict_when_retries_are_disabled() {
+        // Arrange
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        // Act
+        let result: Result<(), DomainError> = retry_on_conflict(0, || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(DomainError::Conflict(String::from("conflict")))
+        })
+        .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Conflict(_))));
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn increment_product_inventory_command_handler_returns_err_when_quantity_is_zero() {
+        // Arrange
+        let increment_command = IncrementProdcuctReservedInventoryCommand {
+            product_id: String::from("1"),
+            cart_id: Some(String::from("cart")),
+            quantity: 0,
+            message_id: None,
+        };
+
+        let handler: IncrementProdcuctInventoryCommandHandler =
+            IncrementProdcuctInventoryCommandHandler::new(
+                Arc::new(MockUnitOfWork::new()),
+                Duration::from_secs(60),
+            );
+
+        // Act
+        let result = handler.handle(&increment_command).await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[test]
+    fn inventory_changed_events_include_out_of_stock_when_sellable_inventory_runs_out() {
+        // Arrange
+        let product = Product {
+            available_inventory: 2,
+            reserved_inventory: 2,
+            version: 1,
+            ..product("1", "laptop", 10.0)
+        };
+
+        // Act
+        let events = inventory_changed_events(1, &product);
+
+        // Assert
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            Event::ProductInventoryChangedEvent { .. }
+        ));
+        assert!(matches!(events[1], Event::ProductOutOfStockEvent { .. }));
+    }
+
+    #[test]
+    fn inventory_changed_events_include_back_in_stock_when_sellable_inventory_returns() {
+        // Arrange
+        let product = Product {
+            available_inventory: 5,
+            reserved_inventory: 2,
+            version: 1,
+            ..product("1", "laptop", 10.0)
+        };
+
+        // Act
+        let events = inventory_changed_events(0, &product);
+
+        // Assert
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[1],
+            Event::ProductBackInStockEvent {
+                available_quantity: 3,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn commit_ordered_inventory_command_handler_rejects_empty_orders() {
+        // Arrange
+        let mock_uow = MockUnitOfWork::new();
+        let handler = CommitOrderedInventoryCommandHandler::new(Arc::new(mock_uow));
+        let commit_command = CommitOrderedInventoryCommand {
+            order_id: String::from("order"),
+            cart_id: Some(String::from("cart")),
+            items: Vec::new(),
+            message_id: None,
+        };
+
+        // Act
+        let result = handler.handle(&commit_command).await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[test]
+    fn product_page_request_rejects_page_sizes_over_the_maximum() {
+        // Arrange
+        let list_products_query = ListProductsQuery {
+            page_size: Some(MAX_PAGE_SIZE + 1),
+            ..ListProductsQuery::default()
+        };
+
+        // Act
+        let result = product_page_request(&list_products_query);
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[test]
+    fn product_page_request_rejects_malformed_page_tokens() {
+        // Arrange
+        let list_products_query = ListProductsQuery {
+            page_token: Some(String::from("not-a-token")),
+            ..ListProductsQuery::default()
+        };
+
+        // Act
+        let result = product_page_request(&list_products_query);
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn list_products_query_handler_returns_next_page_token_until_last_page() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(
+            (1..=3)
+                .map(|id| Product {
+                    created_at_utc: id,
+                    ..product(&id.to_string(), "laptop", 10.0)
+                })
+                .collect(),
+        );
+        let handler = ListProductsQueryHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        let first_page = handler
+            .handle(Some(ListProductsQuery {
+                page_size: Some(2),
+                ..ListProductsQuery::default()
+            }))
+            .await
+            .unwrap();
+        let last_page = handler
+            .handle(Some(ListProductsQuery {
+                page_size: Some(2),
+                page_token: first_page.next_page_token.clone(),
+                ..ListProductsQuery::default()
+            }))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(first_page.total_count, 3);
+        assert_eq!(first_page.products.len(), 2);
+        assert_eq!(first_page.next_page_token, Some(String::from("2")));
+        assert_eq!(last_page.products.len(), 1);
+        assert_eq!(last_page.products[0].id, "3");
+        assert!(last_page.next_page_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn search_products_query_handler_ranks_filters_and_highlights_matches() {
+        // Arrange
+        let stocked = |id, name, description: &str, available_inventory| Product {
+            description: description.to_string(),
+            available_inventory,
+            ..product(id, name, 10.0)
+        };
+        let repositories = InMemoryRepositories::with_products(vec![
+            stocked("1", "Steel pot", "Pairs well with a kettle", 1),
+            stocked("2", "Steel kettle", "Boils water", 1),
+            stocked("3", "Copper kettle", "Sold out", 0),
+            stocked("4", "Frying pan", "Non-stick", 1),
+        ]);
+        let handler = SearchProductsQueryHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        let response = handler
+            .handle(Some(SearchProductsQuery {
+                q: String::from("Kettle"),
+                in_stock: true,
+                ..SearchProductsQuery::default()
+            }))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(response.total_count, 2);
+        assert_eq!(response.results[0].product.id, "2");
+        assert_eq!(
+            response.results[0].highlighted_name,
+            "Steel <em>kettle</em>"
+        );
+        assert_eq!(response.results[1].product.id, "1");
+        assert_eq!(
+            response.results[1].snippet,
+            "Pairs well with a <em>kettle</em>"
+        );
+        assert!(response.next_page_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn search_products_query_handler_rejects_empty_search_text() {
+        // Arrange
+        let handler = SearchProductsQueryHandler::new(Arc::new(MockUnitOfWork::new()));
+
+        // Act
+        let result = handler
+            .handle(Some(SearchProductsQuery {
+                q: String::from(" - "),
+                ..SearchProductsQuery::default()
+            }))
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn batch_get_products_query_handler_lists_ids_that_were_not_found() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![
+            product("1", "laptop", 10.0),
+            product("2", "laptop", 10.0),
+            Product {
+                deleted_at_utc: Some(1),
+                ..product("3", "laptop", 10.0)
+            },
+        ]);
+        let handler = BatchGetProductsQueryHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        let response = handler
+            .handle(Some(BatchGetProductsQuery {
+                ids: ["2", "missing", "1", "3", "2"].map(String::from).to_vec(),
+            }))
+            .await
+            .unwrap();
+
+        // Assert
+        let product_ids: Vec<&str> = response
+            .products
+            .iter()
+            .map(|product| product.id.as_str())
+            .collect();
+        assert_eq!(product_ids, vec!["2", "1"]);
+        assert_eq!(response.not_found_ids, vec!["missing", "3"]);
+    }
+
+    #[tokio::test]
+    async fn batch_get_products_query_handler_requires_at_least_one_id() {
+        // Arrange
+        let handler = BatchGetProductsQueryHandler::new(Arc::new(MockUnitOfWork::new()));
+
+        // Act
+        let result = handler
+            .handle(Some(BatchGetProductsQuery { ids: Vec::new() }))
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Validation(_))));
+    }
+
+    #[test]
+    fn product_slug_is_derived_from_the_name() {
+        // Arrange
+        let name = "  Trail Running Shoes, Size 42!";
+
+        // Act
+        let slug = product_slug(name);
+
+        // Assert
+        assert_eq!(slug, "trail-running-shoes-size-42");
+    }
+
+    #[tokio::test]
+    async fn get_product_query_handler_finds_products_by_slug_and_sku() {
+        // Arrange
+        let kettle = |id, created_at_utc, sku: &str| Product {
+            slug: String::from("steel-kettle"),
+            sku: Some(sku.to_string()),
+            created_at_utc,
+            ..product(id, "Steel kettle", 10.0)
+        };
+        let repositories = InMemoryRepositories::with_products(vec![
+            kettle("newer", 2, "KET-2"),
+            kettle("older", 1, "KET-1"),
+        ]);
+        let handler = GetProductQueryHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        let by_slug = handler
+            .handle(Some(GetProductQuery {
+                key: ProductKey::Slug(String::from("steel-kettle")),
+            }))
+            .await
+            .unwrap();
+        let by_sku = handler
+            .handle(Some(GetProductQuery {
+                key: ProductKey::Sku(String::from("KET-2")),
+            }))
+            .await
+            .unwrap();
+        let missing = handler
+            .handle(Some(GetProductQuery {
+                key: ProductKey::Id(String::from("missing")),
+            }))
+            .await;
+
+        // Assert
+        assert_eq!(by_slug.id, "older");
+        assert_eq!(by_sku.id, "newer");
+        assert!(matches!(missing, Err(DomainError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn deleted_products_are_hidden_from_listing_until_restored() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![
+            product("1", "laptop", 10.0),
+            product("2", "laptop", 10.0),
+        ]);
+        let uow = Arc::new(repositories.mock_uow());
+        let delete_handler = DeleteProductCommandHandler::new(uow.clone());
+        let restore_handler = RestoreProductCommandHandler::new(uow.clone());
+        let list_handler = ListProductsQueryHandler::new(uow);
+
+        // Act
+        delete_handler
+            .handle(&DeleteProductCommand {
+                product_id: String::from("1"),
+                expected_version: None,
+            })
+            .await
+            .unwrap();
+        let after_delete = list_handler.handle(None).await.unwrap();
+        restore_handler
+            .handle(&RestoreProductCommand {
+                product_id: String::from("1"),
+            })
+            .await
+            .unwrap();
+        let after_restore = list_handler.handle(None).await.unwrap();
+
+        // Assert
+        assert_eq!(after_delete.total_count, 1);
+        assert_eq!(after_delete.products[0].id, "2");
+        assert_eq!(after_restore.total_count, 2);
+    }
+
+    #[tokio::test]
+    async fn purge_deleted_products_command_handler_only_purges_products_past_retention() {
+        // Arrange
+        let deleted_at = |id, deleted_at_utc| Product {
+            deleted_at_utc: Some(deleted_at_utc),
+            ..product(id, "laptop", 10.0)
+        };
+        let repositories = InMemoryRepositories::with_products(vec![
+            deleted_at("expired", 10),
+            deleted_at("retained", 100),
+        ]);
+        let handler = PurgeDeletedProductsCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        handler
+            .handle(&PurgeDeletedProductsCommand {
+                deleted_before_utc: 50,
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(matches!(
+            repositories.products.read_deleted("expired").await,
+            Err(DomainError::NotFound(_))
+        ));
+        assert!(repositories.products.read_deleted("retained").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn purge_deleted_products_command_handler_keeps_products_restored_before_the_purge() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            deleted_at_utc: Some(10),
+            ..product("1", "laptop", 10.0)
+        }]);
+        let mut uow = MockUnitOfWork::new();
+        let products = repositories.products.clone();
+        uow.expect_get_product_repository().returning(move || {
+            let products = products.clone();
+            Box::pin(async move { products as _ })
+        });
+        // The product is restored after the purge was scheduled but before its transaction runs
+        let products = repositories.products.clone();
+        uow.expect_begin_transaction().returning(move || {
+            let products = products.clone();
+            Box::pin(async move {
+                let session = client_session().await;
+                let mut restored = products.read_deleted("1").await.unwrap();
+                restored.deleted_at_utc = None;
+                products
+                    .update(restored.id.clone(), restored, session.clone())
+                    .await
+                    .unwrap();
+                Ok(session)
+            })
+        });
+        uow.expect_commit()
+            .returning(|_| Box::pin(async { Ok(()) }));
+        let handler = PurgeDeletedProductsCommandHandler::new(Arc::new(uow));
+
+        // Act
+        let result = handler
+            .handle(&PurgeDeletedProductsCommand {
+                deleted_before_utc: 50,
+            })
+            .await;
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(repositories.products.read("1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn increment_product_inventory_command_handler_reserves_per_cart_and_reports_shortages() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let handler = IncrementProdcuctInventoryCommandHandler::new(
+            Arc::new(repositories.mock_uow()),
+            Duration::from_secs(60),
+        );
+        let add_to_cart = |cart_id: &str, quantity| IncrementProdcuctReservedInventoryCommand {
+            product_id: String::from("1"),
+            cart_id: Some(cart_id.to_string()),
+            quantity,
+            message_id: None,
+        };
+
+        // Act
+        handler.handle(&add_to_cart("first", 3)).await.unwrap();
+        handler.handle(&add_to_cart("first", 4)).await.unwrap();
+        let shortage = handler.handle(&add_to_cart("second", 2)).await;
+
+        // Assert
+        assert!(matches!(shortage, Err(DomainError::InsufficientStock(_))));
+        let reserved_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(reserved_product.reserved_inventory, 4);
+        assert!(repositories
+            .registered_events()
+            .iter()
+            .any(|event| matches!(
+                event,
+                Event::InsufficientStockEvent {
+                    requested_quantity: 2,
+                    available_quantity: 1,
+                    ..
+                }
+            )));
+    }
+
+    #[tokio::test]
+    async fn release_expired_reservations_command_handler_returns_only_expired_stock() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            reserved_inventory: 3,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let reservation = |cart_id: &str, quantity, expires_at_utc| Reservation {
+            id: cart_id.to_string(),
+            cart_id: cart_id.to_string(),
+            product_id: String::from("1"),
+            quantity,
+            created_at_utc: 0,
+            updated_at_utc: 0,
+            expires_at_utc,
+        };
+        let session = client_session().await;
+        for (cart_id, quantity, expires_at_utc) in [("expired", 2, 10), ("fresh", 1, 1000)] {
+            repositories
+                .reservations
+                .upsert(
+                    reservation(cart_id, quantity, expires_at_utc),
+                    session.clone(),
+                )
+                .await
+                .unwrap();
+        }
+        let handler =
+            ReleaseExpiredReservationsCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        handler
+            .handle(&ReleaseExpiredReservationsCommand {
+                expired_before_utc: 100,
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        let released_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(released_product.reserved_inventory, 1);
+        assert!(repositories
+            .reservations
+            .read("expired", "1", session.clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repositories
+            .reservations
+            .read("fresh", "1", session)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(repositories
+            .registered_events()
+            .iter()
+            .any(|event| matches!(event, Event::ReservationExpiredEvent { quantity: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn release_expired_reservations_command_handler_drops_reservations_of_deleted_products() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            reserved_inventory: 2,
+            deleted_at_utc: Some(50),
+            ..product("1", "laptop", 10.0)
+        }]);
+        let session = client_session().await;
+        repositories
+            .reservations
+            .upsert(
+                Reservation {
+                    id: String::from("expired"),
+                    cart_id: String::from("expired"),
+                    product_id: String::from("1"),
+                    quantity: 2,
+                    created_at_utc: 0,
+                    updated_at_utc: 0,
+                    expires_at_utc: 10,
+                },
+                session.clone(),
+            )
+            .await
+            .unwrap();
+        let handler =
+            ReleaseExpiredReservationsCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        handler
+            .handle(&ReleaseExpiredReservationsCommand {
+                expired_before_utc: 100,
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(repositories
+            .reservations
+            .read("expired", "1", session)
+            .await
+            .unwrap()
+            .is_none());
+        let deleted_product = repositories.products.read_deleted("1").await.unwrap();
+        assert_eq!(deleted_product.reserved_inventory, 2);
+        assert!(repositories
+            .registered_events()
+            .iter()
+            .any(|event| matches!(event, Event::ReservationExpiredEvent { quantity: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn increment_product_inventory_command_handler_skips_redelivered_messages() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let handler = IncrementProdcuctInventoryCommandHandler::new(
+            Arc::new(repositories.mock_uow()),
+            Duration::from_secs(60),
+        );
+        let add_to_cart = IncrementProdcuctReservedInventoryCommand {
+            product_id: String::from("1"),
+            cart_id: None,
+            quantity: 2,
+            message_id: Some(String::from("message-1")),
+        };
+
+        // Act
+        handler.handle(&add_to_cart).await.unwrap();
+        handler.handle(&add_to_cart).await.unwrap();
+
+        // Assert
+        let reserved_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(reserved_product.reserved_inventory, 2);
+        assert_eq!(reserved_product.version, 1);
+    }
+
+    #[tokio::test]
+    async fn commit_ordered_inventory_command_handler_leaves_reservations_alone_without_a_cart() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            reserved_inventory: 2,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let handler = CommitOrderedInventoryCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        handler
+            .handle(&CommitOrderedInventoryCommand {
+                order_id: String::from("order"),
+                cart_id: None,
+                items: vec![OrderItem {
+                    product_id: String::from("1"),
+                    quantity: 1,
+                }],
+                message_id: None,
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        let committed_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(committed_product.available_inventory, 4);
+        assert_eq!(committed_product.reserved_inventory, 2);
+    }
+
+    #[tokio::test]
+    async fn commit_ordered_inventory_command_handler_does_not_sell_stock_reserved_by_a_cart() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 2,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let reserve_handler = IncrementProdcuctInventoryCommandHandler::new(
+            Arc::new(repositories.mock_uow()),
+            Duration::from_secs(60),
+        );
+        let commit_handler =
+            CommitOrderedInventoryCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        reserve_handler
+            .handle(&IncrementProdcuctReservedInventoryCommand {
+                product_id: String::from("1"),
+                cart_id: Some(String::from("cart-a")),
+                quantity: 2,
+                message_id: None,
+            })
+            .await
+            .unwrap();
+        let result = commit_handler
+            .handle(&CommitOrderedInventoryCommand {
+                order_id: String::from("order"),
+                cart_id: None,
+                items: vec![OrderItem {
+                    product_id: String::from("1"),
+                    quantity: 1,
+                }],
+                message_id: None,
+            })
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::InsufficientStock(_))));
+        let reserved_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(reserved_product.available_inventory, 2);
+        assert_eq!(reserved_product.reserved_inventory, 2);
+    }
+
+    #[tokio::test]
+    async fn modify_product_inventory_command_handler_rejects_inventory_below_reserved() {
+        // Arrange
+        let repositories = InMemoryRepositories::with_products(vec![Product {
+            available_inventory: 5,
+            reserved_inventory: 3,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let handler = ModifyProductInventoryCommandHandler::new(Arc::new(repositories.mock_uow()));
+
+        // Act
+        let result = handler
+            .handle(&ModifyProductInventoryCommand {
+                product_id: String::from("1"),
+                new_inventory: 2,
+                expected_version: None,
+            })
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::InsufficientStock(_))));
+        let unchanged_product = repositories.products.read("1").await.unwrap();
+        assert_eq!(unchanged_product.available_inventory, 5);
+    }
 }
@@ -1,11 +1,24 @@
+use std::{cmp::Ordering, fmt};
+
 use serde::{Deserialize, Serialize};
 
+use crate::events::Event;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Product {
     pub id: String,
     pub name: String,
     pub price: f32,
     pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub brand: Option<String>,
+    /// URL-friendly form of the name, recomputed whenever the name changes.
+    #[serde(default)]
+    pub slug: String,
+    #[serde(default)]
+    pub sku: Option<String>,
     pub available_inventory: u32,
     pub reserved_inventory: u32,
     pub stars: u8,
@@ -13,4 +26,243 @@ pub struct Product {
     pub created_at_utc: i64,
     pub updated_at_utc: i64,
     pub version: u32,
+    #[serde(default)]
+    pub deleted_at_utc: Option<i64>,
+}
+
+/// The ways a single product can be looked up. Slugs and SKUs are not unique; when several
+/// live products share one, the earliest created wins.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProductKey {
+    Id(String),
+    Slug(String),
+    Sku(String),
+}
+
+impl fmt::Display for ProductKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProductKey::Id(id) => write!(f, "id {}", id),
+            ProductKey::Slug(slug) => write!(f, "slug {}", slug),
+            ProductKey::Sku(sku) => write!(f, "SKU {}", sku),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductSortField {
+    #[default]
+    CreatedAt,
+    Price,
+    Rating,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProductFilter {
+    pub min_price: Option<f32>,
+    pub max_price: Option<f32>,
+    /// Only products with sellable (available minus reserved) inventory.
+    pub in_stock_only: bool,
+    pub min_stars: Option<u8>,
+}
+
+impl ProductFilter {
+    pub fn matches(&self, product: &Product) -> bool {
+        product.deleted_at_utc.is_none()
+            && self
+                .min_price
+                .is_none_or(|min_price| product.price >= min_price)
+            && self
+                .max_price
+                .is_none_or(|max_price| product.price <= max_price)
+            && (!self.in_stock_only || product.available_inventory > product.reserved_inventory)
+            && self
+                .min_stars
+                .is_none_or(|min_stars| product.stars >= min_stars)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductPageRequest {
+    pub filter: ProductFilter,
+    pub sort_by: ProductSortField,
+    pub sort_order: SortOrder,
+    pub offset: u64,
+    pub limit: u32,
+}
+
+impl ProductPageRequest {
+    /// Orders products the same way the database sort does: by the sort field in the
+    /// requested direction, then by id so pages stay stable between requests.
+    pub fn compare(&self, left: &Product, right: &Product) -> Ordering {
+        let by_field = match self.sort_by {
+            ProductSortField::CreatedAt => left.created_at_utc.cmp(&right.created_at_utc),
+            ProductSortField::Price => left.price.total_cmp(&right.price),
+            ProductSortField::Rating => left.stars.cmp(&right.stars),
+            ProductSortField::Name => left.name.cmp(&right.name),
+        };
+        let by_field = match self.sort_order {
+            SortOrder::Asc => by_field,
+            SortOrder::Desc => by_field.reverse(),
+        };
+
+        by_field.then_with(|| left.id.cmp(&right.id))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProductPage {
+    pub products: Vec<Product>,
+    pub total_count: u64,
+}
+
+/// Full-text search over product names and descriptions, ranked by relevance rather than a
+/// sort field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductSearchRequest {
+    pub text: String,
+    pub filter: ProductFilter,
+    pub offset: u64,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProductSearchHit {
+    pub product: Product,
+    pub score: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProductSearchPage {
+    pub hits: Vec<ProductSearchHit>,
+    pub total_count: u64,
+}
+
+/// Lower bounds of the price facet buckets; each bucket runs up to the next bound and the last
+/// one is open-ended.
+pub const PRICE_FACET_BOUNDARIES: [f64; 6] = [0.0, 25.0, 50.0, 100.0, 250.0, 500.0];
+
+/// Index of the price bucket the price falls into.
+pub fn price_facet_bucket(price: f64) -> usize {
+    PRICE_FACET_BOUNDARIES
+        .iter()
+        .rposition(|lower_bound| price >= *lower_bound)
+        .unwrap_or(0)
+}
+
+/// Label of the price bucket, e.g. `25-50` or `500+`.
+pub fn price_facet_value(bucket: usize) -> String {
+    match PRICE_FACET_BOUNDARIES.get(bucket + 1) {
+        Some(upper_bound) => format!("{}-{}", PRICE_FACET_BOUNDARIES[bucket], upper_bound),
+        None => format!("{}+", PRICE_FACET_BOUNDARIES[bucket]),
+    }
+}
+
+pub fn stock_facet_value(in_stock: bool) -> &'static str {
+    match in_stock {
+        true => "in_stock",
+        false => "out_of_stock",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FacetBucket {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Bucket counts over every product matching a listing or search, not just the current page.
+/// Empty buckets are left out; price and rating buckets are in ascending order, stock lists
+/// in-stock first, and category and brand are ordered by count.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProductFacets {
+    pub price: Vec<FacetBucket>,
+    pub rating: Vec<FacetBucket>,
+    pub stock: Vec<FacetBucket>,
+    pub category: Vec<FacetBucket>,
+    pub brand: Vec<FacetBucket>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    pub id: String,
+    pub cart_id: String,
+    pub product_id: String,
+    pub quantity: u32,
+    pub created_at_utc: i64,
+    pub updated_at_utc: i64,
+    pub expires_at_utc: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub id: String,
+    pub event: Event,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    pub created_at_utc: i64,
+    pub delivered_at_utc: Option<i64>,
+    pub attempts: u32,
+    pub next_attempt_at_utc: i64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessedMessage {
+    pub id: String,
+    pub processed_at_utc: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomainError {
+    NotFound(String),
+    Validation(String),
+    Conflict(String),
+    InsufficientStock(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Infrastructure(String),
+    /// Mongo aborted the transaction with a retryable label; only seen inside the unit of work,
+    /// which retries the whole transaction and reports `Infrastructure` once it gives up.
+    Transient(String),
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomainError::NotFound(message)
+            | DomainError::Validation(message)
+            | DomainError::Conflict(message)
+            | DomainError::InsufficientStock(message)
+            | DomainError::Unauthorized(message)
+            | DomainError::Forbidden(message)
+            | DomainError::Infrastructure(message)
+            | DomainError::Transient(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl DomainError {
+    /// Stable, machine-readable code returned to API clients alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DomainError::NotFound(_) => "not_found",
+            DomainError::Validation(_) => "validation_failed",
+            DomainError::Conflict(_) => "conflict",
+            DomainError::InsufficientStock(_) => "insufficient_stock",
+            DomainError::Unauthorized(_) => "unauthorized",
+            DomainError::Forbidden(_) => "forbidden",
+            DomainError::Infrastructure(_) | DomainError::Transient(_) => "internal_error",
+        }
+    }
 }
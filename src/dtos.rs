@@ -8,17 +8,71 @@ pub struct ProductResponse {
     pub name: String,
     pub price: f32,
     pub description: String,
+    pub category: Option<String>,
+    pub brand: Option<String>,
+    pub slug: String,
+    pub sku: Option<String>,
     pub available_inventory: u32,
     pub reserved_inventory: u32,
     pub stars: u8,
     pub number_of_reviews: u32,
+    pub version: u32,
 }
 
+impl Response for ProductResponse {}
+
+#[derive(Deserialize, Serialize)]
+pub struct BatchGetProductsResponse {
+    /// Found products, in the order their ids were requested.
+    pub products: Vec<ProductResponse>,
+    pub not_found_ids: Vec<String>,
+}
+impl Response for BatchGetProductsResponse {}
+
 #[derive(Deserialize, Serialize)]
-pub struct GetProductsResponse {
+pub struct ListProductsResponse {
     pub products: Vec<ProductResponse>,
+    pub total_count: u64,
+    pub next_page_token: Option<String>,
+    /// Present only when the request asked for facets.
+    pub facets: Option<ProductFacetsResponse>,
+}
+impl Response for ListProductsResponse {}
+
+#[derive(Deserialize, Serialize)]
+pub struct FacetBucketResponse {
+    pub value: String,
+    pub count: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ProductFacetsResponse {
+    pub price: Vec<FacetBucketResponse>,
+    pub rating: Vec<FacetBucketResponse>,
+    pub stock: Vec<FacetBucketResponse>,
+    pub category: Vec<FacetBucketResponse>,
+    pub brand: Vec<FacetBucketResponse>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ProductSearchResult {
+    pub product: ProductResponse,
+    pub score: f64,
+    /// The product name with matching words wrapped in `<em>` tags.
+    pub highlighted_name: String,
+    /// The part of the description around the first match, highlighted the same way.
+    pub snippet: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SearchProductsResponse {
+    pub results: Vec<ProductSearchResult>,
+    pub total_count: u64,
+    pub next_page_token: Option<String>,
+    /// Present only when the request asked for facets.
+    pub facets: Option<ProductFacetsResponse>,
 }
-impl Response for GetProductsResponse {}
+impl Response for SearchProductsResponse {}
 
 #[derive(Deserialize, Serialize)]
 pub struct CreateProductResponse {
@@ -28,10 +82,18 @@ impl Response for CreateProductResponse {}
 
 #[derive(Deserialize, Serialize)]
 pub struct ApiError {
+    pub code: String,
     pub error: String,
 }
 impl Response for ApiError {}
 
+#[derive(Deserialize, Serialize)]
+pub struct HealthResponse {
+    pub status: String,
+    pub last_error: Option<String>,
+}
+impl Response for HealthResponse {}
+
 #[derive(Deserialize, Serialize)]
 pub struct EmptyResponse {}
 impl Response for EmptyResponse {}
@@ -1,38 +1,74 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use amqprs::{
-    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
+    callbacks::{ChannelCallback, DefaultChannelCallback, DefaultConnectionCallback},
     channel::{
-        self, BasicConsumeArguments, BasicPublishArguments, Channel, ExchangeBindArguments,
-        ExchangeDeclareArguments, ExchangeType, QueueBindArguments, QueueDeclareArguments,
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicPublishArguments,
+        BasicQosArguments, Channel, ConfirmSelectArguments, ExchangeDeclareArguments, ExchangeType,
+        QueueBindArguments, QueueDeclareArguments,
     },
     connection::{Connection, OpenConnectionArguments},
-    consumer::{AsyncConsumer, DefaultConsumer},
-    BasicProperties, Deliver, DELIVERY_MODE_PERSISTENT,
+    consumer::AsyncConsumer,
+    error::Error as AmqpError,
+    Ack, BasicProperties, Cancel, CloseChannel, Deliver, FieldTable, FieldValue, Nack, Return,
+    DELIVERY_MODE_PERSISTENT,
 };
 use async_trait::async_trait;
-use axum::extract::State;
+use futures_util::{
+    future::join_all,
+    stream::{self, StreamExt},
+};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock, Semaphore};
 use tracing::{event, Level};
 
 use crate::{
     cqrs::{
-        CommandHandler, DecrementProductReservedInventoryCommand, GetProductsQuery,
-        IncrementProdcuctReservedInventoryCommand, QueryHandler,
+        CommandHandler, CommitOrderedInventoryCommand, CommitOrderedInventoryCommandHandler,
+        DecrementProductInventoryCommandHandler, DecrementProductReservedInventoryCommand,
+        IncrementProdcuctInventoryCommandHandler, IncrementProdcuctReservedInventoryCommand,
+        RestockOrderedInventoryCommand, RestockOrderedInventoryCommandHandler,
     },
-    dtos::GetProductsResponse,
-    state::AppState,
+    domain::DomainError,
 };
 
+pub static PRODUCT_CREATED_EXCHANGE_NAME: &str = "product.created";
+pub static PRODUCT_UPDATED_EXCHANGE_NAME: &str = "product.updated";
+pub static PRODUCT_PRICE_CHANGED_EXCHANGE_NAME: &str = "product.price.changed";
+pub static PRODUCT_INVENTORY_CHANGED_EXCHANGE_NAME: &str = "product.inventory.changed";
+pub static PRODUCT_OUT_OF_STOCK_EXCHANGE_NAME: &str = "product.out.of.stock";
+pub static PRODUCT_BACK_IN_STOCK_EXCHANGE_NAME: &str = "product.back.in.stock";
 pub static PRODUCT_ADDED_TO_CART_QUEUE_NAME: &str = "product.added.to.cart";
 pub static PRODUCT_REMOVED_FROM_CART_QUEUE_NAME: &str = "product.removed.from.cart";
+pub static PRODUCT_INSUFFICIENT_STOCK_EXCHANGE_NAME: &str = "product.insufficient.stock";
+pub static PRODUCT_RESERVATION_EXPIRED_EXCHANGE_NAME: &str = "product.reservation.expired";
+pub static ORDER_PLACED_QUEUE_NAME: &str = "order.placed";
+pub static ORDER_CANCELLED_QUEUE_NAME: &str = "order.cancelled";
+pub static ORDER_RETURNED_QUEUE_NAME: &str = "order.returned";
+
+pub static EVENT_SCHEMA_VERSION: u32 = 1;
+pub static EVENT_PRODUCER: &str = "eshop-product-service";
+pub static EVENT_CONTENT_TYPE: &str = "application/json";
+pub static RETRY_COUNT_HEADER: &str = "x-retry-count";
+static DEAD_LETTER_EXCHANGE_ARGUMENT: &str = "x-dead-letter-exchange";
+static DEAD_LETTER_ROUTING_KEY_ARGUMENT: &str = "x-dead-letter-routing-key";
+static MESSAGE_TTL_ARGUMENT: &str = "x-message-ttl";
+// Binds a queue to every routing key on its exchange, the way a fanout exchange would
+static MATCH_ALL_BINDING_KEY: &str = "#";
 
 pub struct RabbitMqInitializationInfo {
     uri: String,
     port: u16,
     username: String,
     password: String,
+    consumer_max_retries: u32,
 }
 
 impl RabbitMqInitializationInfo {
@@ -41,256 +77,2174 @@ impl RabbitMqInitializationInfo {
         port: u16,
         username: String,
         password: String,
+        consumer_max_retries: u32,
     ) -> RabbitMqInitializationInfo {
         RabbitMqInitializationInfo {
-            uri: uri,
-            port: port,
-            username: username,
-            password: password,
+            uri,
+            port,
+            username,
+            password,
+            consumer_max_retries,
         }
     }
 }
 
 // events
-#[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     ProductCreatedEvent {
         id: String,
         name: String,
         price: f32,
     },
+    ProductUpdatedEvent {
+        id: String,
+        name: String,
+        price: f32,
+        description: String,
+        version: u32,
+    },
+    ProductPriceChangedEvent {
+        id: String,
+        previous_price: f32,
+        price: f32,
+    },
+    ProductInventoryChangedEvent {
+        id: String,
+        available_inventory: u32,
+        reserved_inventory: u32,
+    },
+    ProductOutOfStockEvent {
+        id: String,
+    },
+    ProductBackInStockEvent {
+        id: String,
+        available_quantity: u32,
+    },
     ProductAddedToCartEvent {
         product_id: String,
+        #[serde(default)]
+        cart_id: Option<String>,
+        #[serde(default = "default_event_quantity")]
+        quantity: u32,
     },
     ProductRemovedFromCartEvent {
         product_id: String,
+        #[serde(default)]
+        cart_id: Option<String>,
+        #[serde(default = "default_event_quantity")]
+        quantity: u32,
+    },
+    InsufficientStockEvent {
+        product_id: String,
+        cart_id: Option<String>,
+        requested_quantity: u32,
+        available_quantity: u32,
+    },
+    ReservationExpiredEvent {
+        reservation_id: String,
+        cart_id: String,
+        product_id: String,
+        quantity: u32,
+    },
+    OrderPlacedEvent {
+        order_id: String,
+        #[serde(default)]
+        cart_id: Option<String>,
+        items: Vec<OrderItem>,
+    },
+    OrderCancelledEvent {
+        order_id: String,
+        items: Vec<OrderItem>,
+    },
+    OrderReturnedEvent {
+        order_id: String,
+        items: Vec<OrderItem>,
     },
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderItem {
+    pub product_id: String,
+    pub quantity: u32,
+}
+
+// Cart events published before reservations were cart-scoped always meant a single unit
+fn default_event_quantity() -> u32 {
+    1
+}
+
+impl Event {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Event::ProductCreatedEvent { .. } => "ProductCreatedEvent",
+            Event::ProductUpdatedEvent { .. } => "ProductUpdatedEvent",
+            Event::ProductPriceChangedEvent { .. } => "ProductPriceChangedEvent",
+            Event::ProductInventoryChangedEvent { .. } => "ProductInventoryChangedEvent",
+            Event::ProductOutOfStockEvent { .. } => "ProductOutOfStockEvent",
+            Event::ProductBackInStockEvent { .. } => "ProductBackInStockEvent",
+            Event::ProductAddedToCartEvent { .. } => "ProductAddedToCartEvent",
+            Event::ProductRemovedFromCartEvent { .. } => "ProductRemovedFromCartEvent",
+            Event::InsufficientStockEvent { .. } => "InsufficientStockEvent",
+            Event::ReservationExpiredEvent { .. } => "ReservationExpiredEvent",
+            Event::OrderPlacedEvent { .. } => "OrderPlacedEvent",
+            Event::OrderCancelledEvent { .. } => "OrderCancelledEvent",
+            Event::OrderReturnedEvent { .. } => "OrderReturnedEvent",
+        }
+    }
+}
+
+// envelopes
+tokio::task_local! {
+    /// Correlation id of the message currently being handled, inherited by any events it causes.
+    pub static CORRELATION_ID: String;
+}
+
+pub fn current_correlation_id() -> Option<String> {
+    CORRELATION_ID
+        .try_with(|correlation_id| correlation_id.clone())
+        .ok()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub id: String,
+    pub timestamp_utc: i64,
+    pub schema_version: u32,
+    pub correlation_id: String,
+    pub producer: String,
+    pub event_type: String,
+    pub event: Event,
+}
+
+impl EventEnvelope {
+    pub fn new(event: Event) -> EventEnvelope {
+        EventEnvelope::from_parts(
+            uuid::Uuid::new_v4().to_string(),
+            chrono::Utc::now().timestamp_millis(),
+            current_correlation_id(),
+            event,
+        )
+    }
+
+    /// A message without a correlation id starts a new chain, correlated by its own id.
+    pub fn from_parts(
+        id: String,
+        timestamp_utc: i64,
+        correlation_id: Option<String>,
+        event: Event,
+    ) -> EventEnvelope {
+        EventEnvelope {
+            correlation_id: correlation_id.unwrap_or_else(|| id.clone()),
+            id,
+            timestamp_utc,
+            schema_version: EVENT_SCHEMA_VERSION,
+            producer: EVENT_PRODUCER.to_string(),
+            event_type: event.event_type().to_string(),
+            event,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IncomingMessage {
+    Enveloped(EventEnvelope),
+    Legacy(Event),
+}
+
+/// Accepts both enveloped events and the bare `Event` format published before envelopes existed.
+pub fn parse_incoming_event(raw_event: &str) -> Result<EventEnvelope, String> {
+    match serde_json::from_str::<IncomingMessage>(raw_event) {
+        Ok(IncomingMessage::Enveloped(envelope)) => Ok(envelope),
+        Ok(IncomingMessage::Legacy(event)) => Ok(EventEnvelope::new(event)),
+        Err(e) => Err(format!("Failed to deserialize event: {}", e)),
+    }
+}
+
+// routing
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRoute {
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+impl EventRoute {
+    pub fn new(exchange: &str, routing_key: &str) -> EventRoute {
+        EventRoute {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventRoutingTable {
+    routes: HashMap<String, EventRoute>,
+}
+
+impl Default for EventRoutingTable {
+    fn default() -> Self {
+        let mut routes = HashMap::new();
+        routes.insert(
+            String::from("ProductCreatedEvent"),
+            EventRoute::new(PRODUCT_CREATED_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductUpdatedEvent"),
+            EventRoute::new(PRODUCT_UPDATED_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductPriceChangedEvent"),
+            EventRoute::new(PRODUCT_PRICE_CHANGED_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductInventoryChangedEvent"),
+            EventRoute::new(PRODUCT_INVENTORY_CHANGED_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductOutOfStockEvent"),
+            EventRoute::new(PRODUCT_OUT_OF_STOCK_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductBackInStockEvent"),
+            EventRoute::new(PRODUCT_BACK_IN_STOCK_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductAddedToCartEvent"),
+            EventRoute::new(PRODUCT_ADDED_TO_CART_QUEUE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ProductRemovedFromCartEvent"),
+            EventRoute::new(PRODUCT_REMOVED_FROM_CART_QUEUE_NAME, ""),
+        );
+        routes.insert(
+            String::from("InsufficientStockEvent"),
+            EventRoute::new(PRODUCT_INSUFFICIENT_STOCK_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("ReservationExpiredEvent"),
+            EventRoute::new(PRODUCT_RESERVATION_EXPIRED_EXCHANGE_NAME, ""),
+        );
+        routes.insert(
+            String::from("OrderPlacedEvent"),
+            EventRoute::new(ORDER_PLACED_QUEUE_NAME, ""),
+        );
+        routes.insert(
+            String::from("OrderCancelledEvent"),
+            EventRoute::new(ORDER_CANCELLED_QUEUE_NAME, ""),
+        );
+        routes.insert(
+            String::from("OrderReturnedEvent"),
+            EventRoute::new(ORDER_RETURNED_QUEUE_NAME, ""),
+        );
+
+        EventRoutingTable { routes }
+    }
+}
+
+impl EventRoutingTable {
+    /// Applies overrides in the form `EventType=exchange[:routing.key]`, separated by `;`.
+    pub fn with_overrides(mut self, overrides: &str) -> Result<EventRoutingTable, String> {
+        for entry in overrides
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            match entry.split_once('=') {
+                Some((event_type, destination)) => {
+                    let event_type = event_type.trim();
+                    if !self.routes.contains_key(event_type) {
+                        return Err(format!("Unknown event type in route {}", entry));
+                    }
+
+                    let (exchange, routing_key) =
+                        destination.split_once(':').unwrap_or((destination, ""));
+                    if exchange.trim().is_empty() {
+                        return Err(format!("Missing exchange in route {}", entry));
+                    }
+
+                    self.routes.insert(
+                        event_type.to_string(),
+                        EventRoute::new(exchange.trim(), routing_key.trim()),
+                    );
+                }
+                None => return Err(format!("Invalid route {}", entry)),
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn exchanges(&self) -> Vec<String> {
+        let mut exchanges: Vec<String> = self
+            .routes
+            .values()
+            .map(|route| route.exchange.clone())
+            .collect();
+        exchanges.sort();
+        exchanges.dedup();
+        exchanges
+    }
+
+    /// Keys binding the exchange's queue to the routes that publish on it; routes without a
+    /// routing key bind it to every key.
+    pub fn binding_keys(&self, exchange: &str) -> Vec<String> {
+        let mut binding_keys: Vec<String> = self
+            .routes
+            .values()
+            .filter(|route| route.exchange == exchange)
+            .map(|route| match route.routing_key.is_empty() {
+                true => MATCH_ALL_BINDING_KEY.to_string(),
+                false => route.routing_key.clone(),
+            })
+            .collect();
+        if binding_keys.is_empty() {
+            binding_keys.push(MATCH_ALL_BINDING_KEY.to_string());
+        }
+        binding_keys.sort();
+        binding_keys.dedup();
+        binding_keys
+    }
+
+    pub fn route_for(&self, event: &Event) -> Result<&EventRoute, String> {
+        match self.routes.get(event.event_type()) {
+            Some(route) => Ok(route),
+            None => Err(format!("No route configured for {}", event.event_type())),
+        }
+    }
+}
+
 #[async_trait]
 pub trait MessageBroker {
-    async fn publish_message(&self, event: &Event, destination_name: &str) -> Result<(), String>;
-    async fn consume(&self, source_queue_name: &'static str, state: Arc<AppState>);
+    async fn publish_envelope(&self, envelope: &EventEnvelope) -> Result<(), String>;
+    /// Subscribes every registration and keeps consuming for the life of the service.
+    async fn subscribe(&self, registry: HandlerRegistry);
+}
+
+// publisher confirms
+const PUBLISH_CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct PendingConfirms {
+    next_delivery_tag: u64,
+    waiting: BTreeMap<u64, oneshot::Sender<bool>>,
+}
+
+impl PendingConfirms {
+    // The broker numbers confirms per channel starting at 1, in publish order
+    fn register(&mut self) -> oneshot::Receiver<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.next_delivery_tag += 1;
+        self.waiting.insert(self.next_delivery_tag, sender);
+        receiver
+    }
+
+    fn settle(&mut self, delivery_tag: u64, multiple: bool, acked: bool) {
+        let settled = match multiple {
+            true => {
+                let still_waiting = self.waiting.split_off(&(delivery_tag + 1));
+                std::mem::replace(&mut self.waiting, still_waiting)
+            }
+            false => self
+                .waiting
+                .remove(&delivery_tag)
+                .map(|sender| BTreeMap::from([(delivery_tag, sender)]))
+                .unwrap_or_default(),
+        };
+
+        for sender in settled.into_values() {
+            let _ = sender.send(acked);
+        }
+    }
+}
+
+struct PublisherConfirmCallback {
+    pending_confirms: Arc<std::sync::Mutex<PendingConfirms>>,
+}
+
+#[async_trait]
+impl ChannelCallback for PublisherConfirmCallback {
+    async fn close(&mut self, channel: &Channel, close: CloseChannel) -> Result<(), AmqpError> {
+        event!(
+            Level::WARN,
+            "Publisher channel {} closed: {}",
+            channel,
+            close
+        );
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _: &Channel, _: Cancel) -> Result<(), AmqpError> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _: &Channel, _: bool) -> Result<bool, AmqpError> {
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, ack: Ack) {
+        self.pending_confirms
+            .lock()
+            .unwrap()
+            .settle(ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, _: &Channel, nack: Nack) {
+        self.pending_confirms
+            .lock()
+            .unwrap()
+            .settle(nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(&mut self, _: &Channel, ret: Return, _: BasicProperties, _: Vec<u8>) {
+        event!(Level::WARN, "Broker returned an unroutable event: {}", ret);
+    }
+}
+
+// A confirm-mode channel per destination, reused across publishes
+struct PublisherChannel {
+    channel: Channel,
+    pending_confirms: Arc<std::sync::Mutex<PendingConfirms>>,
+    publish_lock: Mutex<()>,
+}
+
+impl PublisherChannel {
+    async fn publish_and_confirm(
+        &self,
+        delivery_properties: BasicProperties,
+        content: Vec<u8>,
+        route: &EventRoute,
+    ) -> Result<(), String> {
+        let _publish_guard = self.publish_lock.lock().await;
+        let confirmation = self.pending_confirms.lock().unwrap().register();
+
+        if let Err(e) = self
+            .channel
+            .basic_publish(
+                delivery_properties,
+                content,
+                BasicPublishArguments::new(&route.exchange, &route.routing_key),
+            )
+            .await
+        {
+            return Err(format!("Failed to publish event to broker: {}", e));
+        }
+
+        match tokio::time::timeout(PUBLISH_CONFIRM_TIMEOUT, confirmation).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => Err(String::from("Broker nacked the published event")),
+            Ok(Err(_)) => Err(String::from(
+                "Publisher channel closed before the event was confirmed",
+            )),
+            Err(_) => Err(String::from(
+                "Timed out waiting for the broker to confirm the event",
+            )),
+        }
+    }
 }
 
 // event brokers
+fn delivery_properties(envelope: &EventEnvelope) -> BasicProperties {
+    let mut delivery_properties = BasicProperties::default();
+    delivery_properties
+        .with_delivery_mode(DELIVERY_MODE_PERSISTENT)
+        .with_message_id(&envelope.id)
+        .with_correlation_id(&envelope.correlation_id)
+        .with_timestamp((envelope.timestamp_utc / 1000) as u64)
+        .with_content_type(EVENT_CONTENT_TYPE)
+        .with_message_type(&envelope.event_type)
+        .with_app_id(&envelope.producer);
+    delivery_properties
+}
+
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const CONSUMER_RESTART_DELAY: Duration = Duration::from_secs(1);
+const CONSUMER_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+#[derive(Debug, Default)]
+pub struct BrokerHealth {
+    connected: AtomicBool,
+    last_error: std::sync::Mutex<Option<String>>,
+}
+
+impl BrokerHealth {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    fn record_connected(&self) {
+        self.connected.store(true, Ordering::SeqCst);
+        *self.last_error.lock().unwrap() = None;
+    }
+
+    fn record_disconnected(&self, error: &str) {
+        self.connected.store(false, Ordering::SeqCst);
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+}
+
+async fn open_connection(init_info: &RabbitMqInitializationInfo) -> Result<Connection, String> {
+    match Connection::open(&OpenConnectionArguments::new(
+        &init_info.uri,
+        init_info.port,
+        &init_info.username,
+        &init_info.password,
+    ))
+    .await
+    {
+        Ok(connection) => {
+            match connection
+                .register_callback(DefaultConnectionCallback)
+                .await
+            {
+                Ok(()) => Ok(connection),
+                Err(e) => Err(format!("Failed to register connection callback: {}", e)),
+            }
+        }
+        Err(e) => Err(format!("Failed to open RabbitMQ connection: {}", e)),
+    }
+}
+
+fn dead_lettering_queue_arguments(queue_name: &str) -> FieldTable {
+    let mut queue_arguments = FieldTable::new();
+    queue_arguments.insert(
+        DEAD_LETTER_EXCHANGE_ARGUMENT.try_into().unwrap(),
+        FieldValue::S(
+            dead_letter_exchange_name(queue_name)
+                .as_str()
+                .try_into()
+                .unwrap(),
+        ),
+    );
+    queue_arguments
+}
+
+// Retries wait out their delay on the retry queue, then expire back onto the queue itself
+// through the default exchange
+fn retry_queue_arguments(queue_name: &str) -> FieldTable {
+    let mut queue_arguments = FieldTable::new();
+    queue_arguments.insert(
+        MESSAGE_TTL_ARGUMENT.try_into().unwrap(),
+        FieldValue::I(CONSUMER_RETRY_DELAY.as_millis() as i32),
+    );
+    queue_arguments.insert(
+        DEAD_LETTER_EXCHANGE_ARGUMENT.try_into().unwrap(),
+        FieldValue::S("".try_into().unwrap()),
+    );
+    queue_arguments.insert(
+        DEAD_LETTER_ROUTING_KEY_ARGUMENT.try_into().unwrap(),
+        FieldValue::S(queue_name.try_into().unwrap()),
+    );
+    queue_arguments
+}
+
+// Every queue gets its own dead-letter exchange and queue for poison messages, and a retry
+// queue delaying redeliveries
+async fn declare_topology(
+    channel: &Channel,
+    destination: &str,
+    binding_keys: &[String],
+) -> Result<(), String> {
+    let dead_letter_exchange = dead_letter_exchange_name(destination);
+    let dead_letter_queue = dead_letter_queue_name(destination);
+    let retry_queue = retry_queue_name(destination);
+
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(
+            destination,
+            &ExchangeType::Topic.to_string(),
+        ))
+        .await
+        .map_err(|e| format!("Failed to declare exchange {}: {}", destination, e))?;
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(
+            &dead_letter_exchange,
+            &ExchangeType::Fanout.to_string(),
+        ))
+        .await
+        .map_err(|e| format!("Failed to declare exchange {}: {}", dead_letter_exchange, e))?;
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(
+            &dead_letter_queue,
+        ))
+        .await
+        .map_err(|e| format!("Failed to declare queue {}: {}", dead_letter_queue, e))?;
+    channel
+        .queue_bind(QueueBindArguments::new(
+            &dead_letter_queue,
+            &dead_letter_exchange,
+            "",
+        ))
+        .await
+        .map_err(|e| format!("Failed to bind queue {}: {}", dead_letter_queue, e))?;
+    channel
+        .queue_declare(
+            QueueDeclareArguments::durable_client_named(&retry_queue)
+                .arguments(retry_queue_arguments(destination))
+                .finish(),
+        )
+        .await
+        .map_err(|e| format!("Failed to declare queue {}: {}", retry_queue, e))?;
+    // Queues created before dead-lettering have no arguments, and RabbitMQ refuses to
+    // redeclare them with some; the README describes recreating them
+    channel
+        .queue_declare(
+            QueueDeclareArguments::durable_client_named(destination)
+                .arguments(dead_lettering_queue_arguments(destination))
+                .finish(),
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to declare queue {}, it may need recreating with dead-lettering: {}",
+                destination, e
+            )
+        })?;
+    for binding_key in binding_keys {
+        channel
+            .queue_bind(QueueBindArguments::new(
+                destination,
+                destination,
+                binding_key,
+            ))
+            .await
+            .map_err(|e| format!("Failed to bind queue {}: {}", destination, e))?;
+    }
+
+    Ok(())
+}
+
 pub struct RabbitMqMessageBroker {
-    connection: Connection,
+    init_info: RabbitMqInitializationInfo,
+    connection: RwLock<Connection>,
+    // Bumped on every reconnect so consumers on the old connection know to restart
+    connection_generation: AtomicU64,
+    health: Arc<BrokerHealth>,
+    routing_table: EventRoutingTable,
+    publisher_channels: Mutex<HashMap<String, Arc<PublisherChannel>>>,
 }
 
 impl RabbitMqMessageBroker {
     pub async fn new(
         init_info: RabbitMqInitializationInfo,
+        routing_table: EventRoutingTable,
     ) -> Result<RabbitMqMessageBroker, String> {
-        match Connection::open(&OpenConnectionArguments::new(
-            &init_info.uri,
-            init_info.port,
-            &init_info.username,
-            &init_info.password,
-        ))
-        .await
-        {
-            Ok(connection) => {
-                match connection
-                    .register_callback(DefaultConnectionCallback)
-                    .await
-                {
-                    Ok(()) => Ok(RabbitMqMessageBroker {
-                        connection: connection,
-                    }),
-                    Err(e) => Err(format!("Failed to register connection callback: {}", e)),
-                }
-            }
-            Err(e) => Err(format!("Failed to open RabbitMQ connection: {}", e)),
-        }
+        let connection = open_connection(&init_info).await?;
+
+        let health = Arc::new(BrokerHealth::default());
+        health.record_connected();
+
+        Ok(RabbitMqMessageBroker {
+            init_info,
+            connection: RwLock::new(connection),
+            connection_generation: AtomicU64::new(0),
+            health,
+            routing_table,
+            publisher_channels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn health(&self) -> Arc<BrokerHealth> {
+        self.health.clone()
     }
 
     pub async fn get_channel(&self, destination: &str) -> Result<Channel, String> {
-        match self.connection.open_channel(None).await {
+        match self.connection.read().await.open_channel(None).await {
             Ok(channel) => {
                 channel
                     .register_callback(DefaultChannelCallback)
                     .await
-                    .unwrap();
-                channel
-                    .exchange_declare(ExchangeDeclareArguments::new(
-                        destination,
-                        &ExchangeType::Fanout.to_string(),
-                    ))
-                    .await
-                    .unwrap();
-                channel
-                    .queue_declare(QueueDeclareArguments::durable_client_named(destination))
-                    .await
-                    .unwrap();
-                channel
-                    .queue_bind(QueueBindArguments::new(destination, destination, ""))
-                    .await
-                    .unwrap();
+                    .map_err(|e| format!("Failed to register channel callback: {}", e))?;
+                declare_topology(
+                    &channel,
+                    destination,
+                    &self.routing_table.binding_keys(destination),
+                )
+                .await?;
 
                 Ok(channel)
             }
             Err(e) => Err(format!("Failed to get channel: {}", e)),
         }
     }
-}
 
-#[async_trait]
-impl MessageBroker for RabbitMqMessageBroker {
-    async fn publish_message(&self, event: &Event, destination_name: &str) -> Result<(), String> {
-        match self.get_channel(destination_name).await {
-            Ok(channel) => {
-                let mut delivery_properties = BasicProperties::default();
-                delivery_properties.with_delivery_mode(DELIVERY_MODE_PERSISTENT);
-                match serde_json::to_string(&event) {
-                    Ok(x) => {
-                        event!(Level::DEBUG, "publishing!!! {}", x);
-                        match channel
-                            .basic_publish(
-                                delivery_properties,
-                                x.into_bytes(),
-                                BasicPublishArguments::new(destination_name, ""),
-                            )
-                            .await
-                        {
-                            Ok(_) => Ok(()),
-                            Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
-                        }
+    async fn publisher_channel(&self, destination: &str) -> Result<Arc<PublisherChannel>, String> {
+        let mut publisher_channels = self.publisher_channels.lock().await;
+
+        if let Some(publisher_channel) = publisher_channels.get(destination) {
+            if publisher_channel.channel.is_open() {
+                return Ok(publisher_channel.clone());
+            }
+        }
+
+        let channel = match self.connection.read().await.open_channel(None).await {
+            Ok(channel) => channel,
+            Err(e) => return Err(format!("Failed to get channel: {}", e)),
+        };
+
+        let pending_confirms = Arc::new(std::sync::Mutex::new(PendingConfirms::default()));
+        channel
+            .register_callback(PublisherConfirmCallback {
+                pending_confirms: pending_confirms.clone(),
+            })
+            .await
+            .map_err(|e| format!("Failed to register channel callback: {}", e))?;
+        declare_topology(
+            &channel,
+            destination,
+            &self.routing_table.binding_keys(destination),
+        )
+        .await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await
+            .map_err(|e| format!("Failed to enable publisher confirms: {}", e))?;
+
+        let publisher_channel = Arc::new(PublisherChannel {
+            channel,
+            pending_confirms,
+            publish_lock: Mutex::new(()),
+        });
+        publisher_channels.insert(destination.to_string(), publisher_channel.clone());
+
+        Ok(publisher_channel)
+    }
+
+    // A failed or unconfirmed publish leaves the channel's confirm numbering unknown
+    async fn evict_publisher_channel(&self, destination: &str) {
+        if let Some(publisher_channel) = self.publisher_channels.lock().await.remove(destination) {
+            let _ = publisher_channel.channel.clone().close().await;
+        }
+    }
+
+    /// Watches the connection and, once it drops, reconnects with exponential backoff,
+    /// re-declares the topology and signals consumers to restart on the new connection.
+    pub async fn supervise_connection(&self) {
+        let mut ticker = tokio::time::interval(CONNECTION_CHECK_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+
+            if self.connection.read().await.is_open() {
+                continue;
+            }
+
+            event!(Level::WARN, "RabbitMQ connection lost, reconnecting");
+            self.health
+                .record_disconnected("RabbitMQ connection was closed");
+            self.reconnect().await;
+        }
+    }
+
+    async fn reconnect(&self) {
+        let mut attempt = 0;
+
+        loop {
+            match open_connection(&self.init_info).await {
+                Ok(connection) => {
+                    *self.connection.write().await = connection;
+                    self.publisher_channels.lock().await.clear();
+
+                    if let Err(e) = self.redeclare_topology().await {
+                        event!(Level::WARN, "Failed to re-declare RabbitMQ topology: {}", e);
                     }
-                    Err(e) => Err(format!("Failed to serialize event: {}", e)),
+
+                    self.connection_generation.fetch_add(1, Ordering::SeqCst);
+                    self.health.record_connected();
+                    event!(
+                        Level::INFO,
+                        "Reconnected to RabbitMQ after {} attempts",
+                        attempt + 1
+                    );
+                    return;
+                }
+                Err(e) => {
+                    let delay = reconnect_delay(attempt);
+                    event!(
+                        Level::WARN,
+                        "Failed to reconnect to RabbitMQ (attempt {}), retrying in {:?}: {}",
+                        attempt + 1,
+                        delay,
+                        e
+                    );
+                    self.health.record_disconnected(&e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
                 }
             }
-            Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
         }
     }
 
-    async fn consume(&self, source_queue_name: &'static str, state: Arc<AppState>) {
-        match self.get_channel(source_queue_name).await {
-            Ok(channel) => {
-                let consume_arguments =
-                    BasicConsumeArguments::new(source_queue_name, "eshop-prodct-service")
-                        .manual_ack(false)
-                        .finish();
-
-                match source_queue_name {
-                    queue_name if queue_name == PRODUCT_ADDED_TO_CART_QUEUE_NAME => {
-                        channel
-                            .basic_consume(
-                                ProductAddedToCartEventHandler::new(Mutex::new(state.clone())),
-                                consume_arguments,
-                            )
-                            .await
-                            .unwrap();
-                    }
-                    queue_name if queue_name == PRODUCT_REMOVED_FROM_CART_QUEUE_NAME => {
-                        channel
-                            .basic_consume(
-                                ProductRemoveFromCartEventHandler::new(Mutex::new(state.clone())),
-                                consume_arguments,
-                            )
-                            .await
-                            .unwrap();
+    async fn redeclare_topology(&self) -> Result<(), String> {
+        for destination in self.routing_table.exchanges() {
+            let channel = self.get_channel(&destination).await?;
+            let _ = channel.close().await;
+        }
+
+        Ok(())
+    }
+
+    async fn start_consumer(
+        &self,
+        registration: &Arc<ConsumerRegistration>,
+    ) -> Result<Channel, String> {
+        let channel = self.get_channel(&registration.queue_name).await?;
+        channel
+            .basic_qos(BasicQosArguments::new(
+                0,
+                registration.prefetch_count,
+                false,
+            ))
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to set prefetch on {}: {}",
+                    registration.queue_name, e
+                )
+            })?;
+
+        let consume_arguments =
+            BasicConsumeArguments::new(&registration.queue_name, &registration.consumer_tag)
+                .manual_ack(true)
+                .finish();
+        let registered_consumer = RegisteredConsumer {
+            registration: registration.clone(),
+            retry_publisher: self.publisher_channel(&registration.queue_name).await?,
+            concurrency_limit: Arc::new(Semaphore::new(registration.max_concurrency)),
+            max_retries: self.init_info.consumer_max_retries,
+        };
+
+        match channel
+            .basic_consume(registered_consumer, consume_arguments)
+            .await
+        {
+            Ok(_) => Ok(channel),
+            Err(e) => Err(format!(
+                "Failed to consume from {}: {}",
+                registration.queue_name, e
+            )),
+        }
+    }
+
+    // Runs for the life of the service, restarting the consumer whenever its channel
+    // closes or the supervisor replaces the connection
+    async fn consume(&self, registration: Arc<ConsumerRegistration>) {
+        loop {
+            let generation = self.connection_generation.load(Ordering::SeqCst);
+
+            match self.start_consumer(&registration).await {
+                Ok(channel) => {
+                    event!(Level::INFO, "Consuming from {}", registration.queue_name);
+
+                    while channel.is_open()
+                        && self.connection_generation.load(Ordering::SeqCst) == generation
+                    {
+                        tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
                     }
-                    x => event!(Level::INFO, "event {} is not valid to subscribe to", x),
+
+                    event!(
+                        Level::WARN,
+                        "Consumer on {} stopped, restarting",
+                        registration.queue_name
+                    );
                 }
+                Err(e) => event!(Level::WARN, "{}", e),
+            }
+
+            tokio::time::sleep(CONSUMER_RESTART_DELAY).await;
+        }
+    }
+}
 
-                let guard = Notify::new();
-                guard.notified().await;
+#[async_trait]
+impl MessageBroker for RabbitMqMessageBroker {
+    async fn publish_envelope(&self, envelope: &EventEnvelope) -> Result<(), String> {
+        let route = self.routing_table.route_for(&envelope.event)?;
+
+        let content = match serde_json::to_string(&envelope) {
+            Ok(x) => {
+                event!(Level::DEBUG, "publishing!!! {}", x);
+                x.into_bytes()
             }
+            Err(e) => return Err(format!("Failed to serialize event: {}", e)),
+        };
+
+        let publisher_channel = self.publisher_channel(&route.exchange).await?;
+        match publisher_channel
+            .publish_and_confirm(delivery_properties(envelope), content, route)
+            .await
+        {
+            Ok(()) => Ok(()),
             Err(e) => {
-                panic!();
+                self.evict_publisher_channel(&route.exchange).await;
+                Err(e)
             }
         }
     }
+
+    async fn subscribe(&self, registry: HandlerRegistry) {
+        join_all(
+            registry
+                .registrations()
+                .iter()
+                .map(|registration| self.consume(registration.clone())),
+        )
+        .await;
+    }
 }
 
-pub struct ProductAddedToCartEventHandler {
-    state: Mutex<Arc<AppState>>,
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub queue_name: String,
+    pub content: Vec<u8>,
+    pub reason: String,
 }
 
-impl ProductAddedToCartEventHandler {
-    pub fn new(state: Mutex<Arc<AppState>>) -> Self {
-        ProductAddedToCartEventHandler { state }
-    }
+struct InMemoryDelivery {
+    basic_properties: BasicProperties,
+    content: Vec<u8>,
 }
 
-#[async_trait]
-impl AsyncConsumer for ProductAddedToCartEventHandler {
-    async fn consume(&mut self, _: &Channel, _: Deliver, _: BasicProperties, content: Vec<u8>) {
-        let state_lock = self.state.lock().await;
+struct InMemoryQueue {
+    sender: mpsc::UnboundedSender<InMemoryDelivery>,
+    // Taken by the first consumer; until then deliveries are buffered in the channel
+    receiver: Option<mpsc::UnboundedReceiver<InMemoryDelivery>>,
+}
 
-        let raw_event = String::from_utf8(content).unwrap();
-        event!(Level::DEBUG, "Received event: {}", raw_event);
+// Enough history for assertions without growing for the life of a long-running service
+const IN_MEMORY_PUBLISHED_EVENTS_CAPACITY: usize = 1000;
 
-        match serde_json::from_str::<Event>(&raw_event) {
-            Ok(deserialized_event) => match deserialized_event {
-                Event::ProductAddedToCartEvent { product_id } => {
-                    let increment_product_inventory_command: IncrementProdcuctReservedInventoryCommand =
-                    IncrementProdcuctReservedInventoryCommand {
-                            product_id: product_id,
-                        };
+/// Delivers events in-process through the same routing table and consumer handlers as
+/// RabbitMQ, recording the most recently published events for assertions.
+pub struct InMemoryMessageBroker {
+    routing_table: EventRoutingTable,
+    consumer_max_retries: u32,
+    queues: Mutex<HashMap<String, InMemoryQueue>>,
+    published_events: std::sync::Mutex<VecDeque<EventEnvelope>>,
+    dead_letters: std::sync::Mutex<Vec<DeadLetter>>,
+    health: Arc<BrokerHealth>,
+}
 
-                    let _ = state_lock
-                        .increment_product_inventory_command_handler
-                        .handle(&increment_product_inventory_command)
-                        .await;
-                }
-                _ => event!(Level::INFO, "Event not supported"),
-            },
-            Err(e) => {
-                event!(
-                    Level::WARN,
-                    "Failed to deserialize event {}: {}",
-                    raw_event,
-                    e
-                );
-            }
+#[allow(dead_code)]
+impl InMemoryMessageBroker {
+    pub fn new(routing_table: EventRoutingTable, consumer_max_retries: u32) -> Self {
+        let health = Arc::new(BrokerHealth::default());
+        health.record_connected();
+
+        InMemoryMessageBroker {
+            routing_table,
+            consumer_max_retries,
+            queues: Mutex::new(HashMap::new()),
+            published_events: std::sync::Mutex::new(VecDeque::new()),
+            dead_letters: std::sync::Mutex::new(Vec::new()),
+            health,
         }
     }
-}
 
-pub struct ProductRemoveFromCartEventHandler {
-    state: Mutex<Arc<AppState>>,
-}
+    pub fn health(&self) -> Arc<BrokerHealth> {
+        self.health.clone()
+    }
 
-impl ProductRemoveFromCartEventHandler {
-    pub fn new(state: Mutex<Arc<AppState>>) -> Self {
-        ProductRemoveFromCartEventHandler { state: state }
+    pub fn published_events(&self) -> Vec<EventEnvelope> {
+        self.published_events
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
     }
-}
 
-#[async_trait]
-impl AsyncConsumer for ProductRemoveFromCartEventHandler {
-    async fn consume(&mut self, _: &Channel, _: Deliver, _: BasicProperties, content: Vec<u8>) {
-        let state_lock = self.state.lock().await;
-
-        let raw_event = String::from_utf8(content).unwrap();
-        event!(Level::DEBUG, "Received event: {}", raw_event);
-
-        match serde_json::from_str::<Event>(&raw_event) {
-            Ok(deserialized_event) => match deserialized_event {
-                Event::ProductRemovedFromCartEvent { product_id } => {
-                    let decrement_product_reserved_inventory_command: DecrementProductReservedInventoryCommand =
-                        DecrementProductReservedInventoryCommand {
-                            product_id: product_id,
-                        };
-
-                    let _ = state_lock
-                        .decrement_product_inventory_command_handler
-                        .handle(&decrement_product_reserved_inventory_command)
-                        .await
-                        .unwrap();
-                }
-                _ => event!(Level::INFO, "event not supported"),
-            },
-            Err(e) => {
+    fn record_published(&self, envelope: &EventEnvelope) {
+        let mut published_events = self.published_events.lock().unwrap();
+        if published_events.len() == IN_MEMORY_PUBLISHED_EVENTS_CAPACITY {
+            published_events.pop_front();
+        }
+        published_events.push_back(envelope.clone());
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().clone()
+    }
+
+    async fn sender_for(&self, queue_name: &str) -> mpsc::UnboundedSender<InMemoryDelivery> {
+        let mut queues = self.queues.lock().await;
+        queues
+            .entry(queue_name.to_string())
+            .or_insert_with(|| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                InMemoryQueue {
+                    sender,
+                    receiver: Some(receiver),
+                }
+            })
+            .sender
+            .clone()
+    }
+
+    // Queues only exist once a consumer registers for them; anything else would be buffered
+    // with nobody to receive it
+    async fn consumed_sender(
+        &self,
+        queue_name: &str,
+    ) -> Option<mpsc::UnboundedSender<InMemoryDelivery>> {
+        self.queues
+            .lock()
+            .await
+            .get(queue_name)
+            .map(|queue| queue.sender.clone())
+    }
+
+    async fn enqueue(&self, queue_name: &str, delivery: InMemoryDelivery) -> Result<(), String> {
+        match self.consumed_sender(queue_name).await {
+            Some(sender) => sender
+                .send(delivery)
+                .map_err(|_| format!("Queue {} is no longer being consumed", queue_name)),
+            None => {
+                event!(
+                    Level::DEBUG,
+                    "No in-memory consumer for {}, dropping delivery",
+                    queue_name
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn settle(&self, queue_name: &str, delivery: InMemoryDelivery, outcome: DeliveryOutcome) {
+        let reason = match outcome {
+            DeliveryOutcome::Handled => return,
+            DeliveryOutcome::Retry(reason) => {
+                let retries = retry_count(&delivery.basic_properties);
+                if retries < self.consumer_max_retries {
+                    let retry_delivery = InMemoryDelivery {
+                        basic_properties: with_retry_count(delivery.basic_properties, retries + 1),
+                        content: delivery.content,
+                    };
+                    // Hold the retry back like the RabbitMQ retry queue does instead of
+                    // handing a failing message straight back to the consumer
+                    if let Some(sender) = self.consumed_sender(queue_name).await {
+                        let queue_name = queue_name.to_string();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(CONSUMER_RETRY_DELAY).await;
+                            if sender.send(retry_delivery).is_err() {
+                                event!(
+                                    Level::WARN,
+                                    "Queue {} is no longer being consumed",
+                                    queue_name
+                                );
+                            }
+                        });
+                    }
+                    return;
+                }
+
+                reason
+            }
+            DeliveryOutcome::DeadLetter(reason) => reason,
+        };
+
+        event!(
+            Level::WARN,
+            "Dead-lettering message from {}: {}",
+            queue_name,
+            reason
+        );
+        self.dead_letters.lock().unwrap().push(DeadLetter {
+            queue_name: queue_name.to_string(),
+            content: delivery.content,
+            reason,
+        });
+    }
+
+    async fn consume(&self, registration: Arc<ConsumerRegistration>) {
+        self.sender_for(&registration.queue_name).await;
+        let receiver = self
+            .queues
+            .lock()
+            .await
+            .get_mut(&registration.queue_name)
+            .and_then(|queue| queue.receiver.take());
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => {
                 event!(
                     Level::WARN,
-                    "Failed to deserialize event {}: {}",
-                    raw_event,
-                    e
+                    "{} already has an in-memory consumer",
+                    registration.queue_name
+                );
+                return;
+            }
+        };
+
+        let deliveries = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|delivery| (delivery, receiver))
+        });
+        deliveries
+            .for_each_concurrent(registration.max_concurrency, |delivery| {
+                let registration = registration.clone();
+                async move {
+                    let outcome = dispatch_delivery(
+                        &registration,
+                        &delivery.content,
+                        &delivery.basic_properties,
+                    )
+                    .await;
+                    self.settle(&registration.queue_name, delivery, outcome)
+                        .await;
+                }
+            })
+            .await;
+    }
+}
+
+#[async_trait]
+impl MessageBroker for InMemoryMessageBroker {
+    async fn publish_envelope(&self, envelope: &EventEnvelope) -> Result<(), String> {
+        let route = self.routing_table.route_for(&envelope.event)?;
+
+        let content = match serde_json::to_vec(&envelope) {
+            Ok(content) => content,
+            Err(e) => return Err(format!("Failed to serialize event: {}", e)),
+        };
+
+        self.record_published(envelope);
+
+        let delivery = InMemoryDelivery {
+            basic_properties: delivery_properties(envelope),
+            content,
+        };
+        self.enqueue(&route.exchange, delivery).await
+    }
+
+    async fn subscribe(&self, registry: HandlerRegistry) {
+        join_all(
+            registry
+                .registrations()
+                .iter()
+                .map(|registration| self.consume(registration.clone())),
+        )
+        .await;
+    }
+}
+
+// consumer acknowledgements
+#[derive(Debug, PartialEq)]
+pub enum DeliveryOutcome {
+    Handled,
+    Retry(String),
+    DeadLetter(String),
+}
+
+impl<R> From<Result<R, DomainError>> for DeliveryOutcome {
+    fn from(result: Result<R, DomainError>) -> Self {
+        match result {
+            Ok(_) => DeliveryOutcome::Handled,
+            // Cart shortages have already been published as an InsufficientStockEvent
+            Err(DomainError::InsufficientStock(_)) => DeliveryOutcome::Handled,
+            // Redelivering cannot fix a malformed message or bring back a missing product
+            Err(DomainError::Validation(message))
+            | Err(DomainError::NotFound(message))
+            | Err(DomainError::Unauthorized(message))
+            | Err(DomainError::Forbidden(message)) => DeliveryOutcome::DeadLetter(message),
+            Err(DomainError::Conflict(message))
+            | Err(DomainError::Infrastructure(message))
+            | Err(DomainError::Transient(message)) => DeliveryOutcome::Retry(message),
+        }
+    }
+}
+
+fn dead_letter_exchange_name(queue_name: &str) -> String {
+    format!("{}.dlx", queue_name)
+}
+
+fn dead_letter_queue_name(queue_name: &str) -> String {
+    format!("{}.dlq", queue_name)
+}
+
+fn retry_queue_name(queue_name: &str) -> String {
+    format!("{}.retry", queue_name)
+}
+
+pub fn retry_count(basic_properties: &BasicProperties) -> u32 {
+    match basic_properties
+        .headers()
+        .and_then(|headers| headers.get(&RETRY_COUNT_HEADER.try_into().unwrap()))
+    {
+        Some(FieldValue::I(count)) => (*count).max(0) as u32,
+        Some(FieldValue::l(count)) => (*count).max(0) as u32,
+        _ => 0,
+    }
+}
+
+fn with_retry_count(basic_properties: BasicProperties, retries: u32) -> BasicProperties {
+    let mut headers = basic_properties.headers().cloned().unwrap_or_default();
+    headers.insert(
+        RETRY_COUNT_HEADER.try_into().unwrap(),
+        FieldValue::I(retries as i32),
+    );
+
+    let mut retry_properties = basic_properties;
+    retry_properties.with_headers(headers);
+    retry_properties
+}
+
+async fn reject_to_dead_letter_queue(channel: &Channel, deliver: &Deliver) {
+    if let Err(e) = channel
+        .basic_nack(BasicNackArguments::new(
+            deliver.delivery_tag(),
+            false,
+            false,
+        ))
+        .await
+    {
+        event!(Level::ERROR, "Failed to dead-letter delivery: {}", e);
+    }
+}
+
+/// Acks handled deliveries, republishes retryable failures to the retry queue with an
+/// incremented retry count until `max_retries` is exhausted, and dead-letters everything else.
+#[allow(clippy::too_many_arguments)]
+async fn settle_delivery(
+    channel: &Channel,
+    retry_publisher: &PublisherChannel,
+    queue_name: &str,
+    deliver: &Deliver,
+    basic_properties: BasicProperties,
+    content: Vec<u8>,
+    outcome: DeliveryOutcome,
+    max_retries: u32,
+) {
+    match outcome {
+        DeliveryOutcome::Handled => {
+            if let Err(e) = channel
+                .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+                .await
+            {
+                event!(Level::ERROR, "Failed to ack delivery: {}", e);
+            }
+        }
+        DeliveryOutcome::Retry(reason) => {
+            let retries = retry_count(&basic_properties);
+            if retries >= max_retries {
+                event!(
+                    Level::ERROR,
+                    "Dead-lettering message from {} after {} retries: {}",
+                    queue_name,
+                    retries,
+                    reason
                 );
+                reject_to_dead_letter_queue(channel, deliver).await;
+                return;
+            }
+
+            event!(
+                Level::WARN,
+                "Retrying message from {} (retry {}): {}",
+                queue_name,
+                retries + 1,
+                reason
+            );
+
+            let retry_properties = with_retry_count(basic_properties, retries + 1);
+
+            // Only ack once the broker has confirmed the retry, so the message is never lost
+            // in between
+            match retry_publisher
+                .publish_and_confirm(
+                    retry_properties,
+                    content,
+                    &EventRoute::new("", &retry_queue_name(queue_name)),
+                )
+                .await
+            {
+                Ok(()) => {
+                    if let Err(e) = channel
+                        .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+                        .await
+                    {
+                        event!(Level::ERROR, "Failed to ack retried delivery: {}", e);
+                    }
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "Failed to republish message for retry: {}", e);
+                    if let Err(e) = channel
+                        .basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, true))
+                        .await
+                    {
+                        event!(Level::ERROR, "Failed to requeue delivery: {}", e);
+                    }
+
+                    // The retry publisher's confirm numbering is unknown now; closing the
+                    // consumer channel restarts the consumer with fresh channels
+                    let _ = retry_publisher.channel.clone().close().await;
+                    let _ = channel.clone().close().await;
+                }
+            }
+        }
+        DeliveryOutcome::DeadLetter(reason) => {
+            event!(
+                Level::WARN,
+                "Dead-lettering message from {}: {}",
+                queue_name,
+                reason
+            );
+            reject_to_dead_letter_queue(channel, deliver).await;
+        }
+    }
+}
+
+// consumer registry
+pub const DEFAULT_PREFETCH_COUNT: u16 = 10;
+pub const DEFAULT_MAX_CONCURRENCY: usize = 1;
+
+#[async_trait]
+pub trait EventHandler {
+    /// Event types this handler accepts; anything else arriving on its queue is dead-lettered.
+    fn event_types(&self) -> &'static [&'static str];
+    async fn handle(&self, event: Event, message_id: String) -> DeliveryOutcome;
+}
+
+pub struct ConsumerRegistration {
+    pub queue_name: String,
+    pub consumer_tag: String,
+    pub prefetch_count: u16,
+    pub max_concurrency: usize,
+    pub handler: Arc<dyn EventHandler + Send + Sync>,
+}
+
+impl ConsumerRegistration {
+    pub fn new(queue_name: &str, handler: Arc<dyn EventHandler + Send + Sync>) -> Self {
+        ConsumerRegistration {
+            queue_name: queue_name.to_string(),
+            consumer_tag: format!("{}.{}", EVENT_PRODUCER, queue_name),
+            prefetch_count: DEFAULT_PREFETCH_COUNT,
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            handler,
+        }
+    }
+
+    pub fn with_prefetch_count(mut self, prefetch_count: u16) -> Self {
+        self.prefetch_count = prefetch_count;
+        self
+    }
+
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct HandlerRegistry {
+    registrations: Vec<Arc<ConsumerRegistration>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        HandlerRegistry::default()
+    }
+
+    /// Registering a queue again replaces its earlier registration.
+    pub fn register(mut self, registration: ConsumerRegistration) -> Self {
+        self.registrations
+            .retain(|existing| existing.queue_name != registration.queue_name);
+        self.registrations.push(Arc::new(registration));
+        self
+    }
+
+    pub fn registrations(&self) -> &[Arc<ConsumerRegistration>] {
+        &self.registrations
+    }
+}
+
+// The AMQP message id survives retries; bare legacy events published without one
+// get a fresh envelope id and so cannot be deduplicated
+fn delivered_message_id(basic_properties: &BasicProperties, envelope: &EventEnvelope) -> String {
+    basic_properties
+        .message_id()
+        .cloned()
+        .unwrap_or_else(|| envelope.id.clone())
+}
+
+async fn dispatch_delivery(
+    registration: &ConsumerRegistration,
+    content: &[u8],
+    basic_properties: &BasicProperties,
+) -> DeliveryOutcome {
+    let raw_event = match std::str::from_utf8(content) {
+        Ok(raw_event) => raw_event,
+        Err(e) => return DeliveryOutcome::DeadLetter(format!("Event was not UTF-8: {}", e)),
+    };
+    event!(Level::DEBUG, "Received event: {}", raw_event);
+
+    let envelope = match parse_incoming_event(raw_event) {
+        Ok(envelope) => envelope,
+        Err(e) => return DeliveryOutcome::DeadLetter(e),
+    };
+
+    let event_type = envelope.event.event_type();
+    if !registration.handler.event_types().contains(&event_type) {
+        return DeliveryOutcome::DeadLetter(format!(
+            "{} is not supported on {}",
+            event_type, registration.queue_name
+        ));
+    }
+
+    let message_id = delivered_message_id(basic_properties, &envelope);
+    CORRELATION_ID
+        .scope(
+            envelope.correlation_id,
+            registration.handler.handle(envelope.event, message_id),
+        )
+        .await
+}
+
+// Deliveries are handled on their own tasks, up to the registration's concurrency limit
+struct RegisteredConsumer {
+    registration: Arc<ConsumerRegistration>,
+    retry_publisher: Arc<PublisherChannel>,
+    concurrency_limit: Arc<Semaphore>,
+    max_retries: u32,
+}
+
+#[async_trait]
+impl AsyncConsumer for RegisteredConsumer {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let concurrency_limit = self.concurrency_limit.clone();
+        let registration = self.registration.clone();
+        let retry_publisher = self.retry_publisher.clone();
+        let channel = channel.clone();
+        let max_retries = self.max_retries;
+        // Waiting for a permit inside the task keeps the callback from stalling the channel's
+        // other deliveries and acknowledgements; prefetch still caps how many can wait
+        tokio::spawn(async move {
+            let permit = match concurrency_limit.acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+
+            let outcome = dispatch_delivery(&registration, &content, &basic_properties).await;
+
+            settle_delivery(
+                &channel,
+                &retry_publisher,
+                &registration.queue_name,
+                &deliver,
+                basic_properties,
+                content,
+                outcome,
+                max_retries,
+            )
+            .await;
+            drop(permit);
+        });
+    }
+}
+
+pub struct ProductAddedToCartEventHandler {
+    command_handler: Arc<IncrementProdcuctInventoryCommandHandler>,
+}
+
+impl ProductAddedToCartEventHandler {
+    pub fn new(command_handler: Arc<IncrementProdcuctInventoryCommandHandler>) -> Self {
+        ProductAddedToCartEventHandler { command_handler }
+    }
+}
+
+#[async_trait]
+impl EventHandler for ProductAddedToCartEventHandler {
+    fn event_types(&self) -> &'static [&'static str] {
+        &["ProductAddedToCartEvent"]
+    }
+
+    async fn handle(&self, event: Event, message_id: String) -> DeliveryOutcome {
+        match event {
+            Event::ProductAddedToCartEvent {
+                product_id,
+                cart_id,
+                quantity,
+            } => {
+                let increment_product_inventory_command: IncrementProdcuctReservedInventoryCommand =
+                    IncrementProdcuctReservedInventoryCommand {
+                        product_id,
+                        cart_id,
+                        quantity,
+                        message_id: Some(message_id),
+                    };
+
+                self.command_handler
+                    .handle(&increment_product_inventory_command)
+                    .await
+                    .into()
+            }
+            other => DeliveryOutcome::DeadLetter(format!("{} not supported", other.event_type())),
+        }
+    }
+}
+
+pub struct ProductRemoveFromCartEventHandler {
+    command_handler: Arc<DecrementProductInventoryCommandHandler>,
+}
+
+impl ProductRemoveFromCartEventHandler {
+    pub fn new(command_handler: Arc<DecrementProductInventoryCommandHandler>) -> Self {
+        ProductRemoveFromCartEventHandler { command_handler }
+    }
+}
+
+#[async_trait]
+impl EventHandler for ProductRemoveFromCartEventHandler {
+    fn event_types(&self) -> &'static [&'static str] {
+        &["ProductRemovedFromCartEvent"]
+    }
+
+    async fn handle(&self, event: Event, message_id: String) -> DeliveryOutcome {
+        match event {
+            Event::ProductRemovedFromCartEvent {
+                product_id,
+                cart_id,
+                quantity,
+            } => {
+                let decrement_product_reserved_inventory_command: DecrementProductReservedInventoryCommand =
+                    DecrementProductReservedInventoryCommand {
+                        product_id,
+                        cart_id,
+                        quantity,
+                        message_id: Some(message_id),
+                    };
+
+                self.command_handler
+                    .handle(&decrement_product_reserved_inventory_command)
+                    .await
+                    .into()
+            }
+            other => DeliveryOutcome::DeadLetter(format!("{} not supported", other.event_type())),
+        }
+    }
+}
+
+pub struct OrderPlacedEventHandler {
+    command_handler: Arc<CommitOrderedInventoryCommandHandler>,
+}
+
+impl OrderPlacedEventHandler {
+    pub fn new(command_handler: Arc<CommitOrderedInventoryCommandHandler>) -> Self {
+        OrderPlacedEventHandler { command_handler }
+    }
+}
+
+#[async_trait]
+impl EventHandler for OrderPlacedEventHandler {
+    fn event_types(&self) -> &'static [&'static str] {
+        &["OrderPlacedEvent"]
+    }
+
+    async fn handle(&self, event: Event, message_id: String) -> DeliveryOutcome {
+        match event {
+            Event::OrderPlacedEvent {
+                order_id,
+                cart_id,
+                items,
+            } => {
+                let commit_ordered_inventory_command = CommitOrderedInventoryCommand {
+                    order_id,
+                    cart_id,
+                    items,
+                    message_id: Some(message_id),
+                };
+
+                match self
+                    .command_handler
+                    .handle(&commit_ordered_inventory_command)
+                    .await
+                {
+                    // Unlike a cart, an order that can't be fulfilled needs a person to look at it
+                    Err(DomainError::InsufficientStock(message)) => {
+                        DeliveryOutcome::DeadLetter(message)
+                    }
+                    result => result.into(),
+                }
+            }
+            other => DeliveryOutcome::DeadLetter(format!("{} not supported", other.event_type())),
+        }
+    }
+}
+
+// Cancelled and returned orders both put their items back on the shelf
+pub struct OrderRestockEventHandler {
+    command_handler: Arc<RestockOrderedInventoryCommandHandler>,
+}
+
+impl OrderRestockEventHandler {
+    pub fn new(command_handler: Arc<RestockOrderedInventoryCommandHandler>) -> Self {
+        OrderRestockEventHandler { command_handler }
+    }
+}
+
+#[async_trait]
+impl EventHandler for OrderRestockEventHandler {
+    fn event_types(&self) -> &'static [&'static str] {
+        &["OrderCancelledEvent", "OrderReturnedEvent"]
+    }
+
+    async fn handle(&self, event: Event, message_id: String) -> DeliveryOutcome {
+        match event {
+            Event::OrderCancelledEvent { order_id, items }
+            | Event::OrderReturnedEvent { order_id, items } => {
+                let restock_ordered_inventory_command = RestockOrderedInventoryCommand {
+                    order_id,
+                    items,
+                    message_id: Some(message_id),
+                };
+
+                self.command_handler
+                    .handle(&restock_ordered_inventory_command)
+                    .await
+                    .into()
+            }
+            other => DeliveryOutcome::DeadLetter(format!("{} not supported", other.event_type())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test]
+    fn legacy_product_added_to_cart_event_defaults_to_single_unscoped_unit() {
+        // Arrange
+        let raw_event = r#"{"ProductAddedToCartEvent":{"product_id":"1"}}"#;
+
+        // Act
+        let result = serde_json::from_str::<Event>(raw_event);
+
+        // Assert
+        match result {
+            Ok(Event::ProductAddedToCartEvent {
+                product_id,
+                cart_id,
+                quantity,
+            }) => {
+                assert_eq!(product_id, "1");
+                assert_eq!(cart_id, None);
+                assert_eq!(quantity, 1);
             }
+            _ => panic!("expected a ProductAddedToCartEvent"),
+        }
+    }
+
+    #[test]
+    fn event_routing_table_applies_overrides_per_event_type() {
+        // Arrange
+        let overrides = "ProductCreatedEvent=product.events:product.created; InsufficientStockEvent=inventory.events";
+
+        // Act
+        let result = EventRoutingTable::default().with_overrides(overrides);
+
+        // Assert
+        let routing_table = result.unwrap();
+        let product_created_event = Event::ProductCreatedEvent {
+            id: String::from("1"),
+            name: String::from("laptop"),
+            price: 1.0,
+        };
+        let reservation_expired_event = Event::ReservationExpiredEvent {
+            reservation_id: String::from("1"),
+            cart_id: String::from("cart"),
+            product_id: String::from("1"),
+            quantity: 1,
+        };
+        assert_eq!(
+            routing_table.route_for(&product_created_event).unwrap(),
+            &EventRoute::new("product.events", "product.created")
+        );
+        assert_eq!(
+            routing_table.route_for(&reservation_expired_event).unwrap(),
+            &EventRoute::new(PRODUCT_RESERVATION_EXPIRED_EXCHANGE_NAME, "")
+        );
+    }
+
+    #[test]
+    fn event_routing_table_binds_queues_to_the_routing_keys_of_their_exchange() {
+        // Arrange
+        let overrides = "ProductCreatedEvent=product.events:product.created; ProductUpdatedEvent=product.events:product.updated";
+
+        // Act
+        let routing_table = EventRoutingTable::default()
+            .with_overrides(overrides)
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            routing_table.binding_keys("product.events"),
+            vec!["product.created", "product.updated"]
+        );
+        assert_eq!(
+            routing_table.binding_keys(PRODUCT_PRICE_CHANGED_EXCHANGE_NAME),
+            vec![MATCH_ALL_BINDING_KEY]
+        );
+        assert_eq!(
+            routing_table.binding_keys(PRODUCT_CREATED_EXCHANGE_NAME),
+            vec![MATCH_ALL_BINDING_KEY]
+        );
+    }
+
+    #[test]
+    fn retry_queue_arguments_expire_retries_back_onto_the_queue() {
+        // Act
+        let queue_arguments = retry_queue_arguments(PRODUCT_ADDED_TO_CART_QUEUE_NAME);
+
+        // Assert
+        let argument = |name: &str| queue_arguments.get(&name.try_into().unwrap()).cloned();
+        assert!(matches!(
+            argument(MESSAGE_TTL_ARGUMENT),
+            Some(FieldValue::I(ttl)) if ttl == CONSUMER_RETRY_DELAY.as_millis() as i32
+        ));
+        assert!(matches!(
+            argument(DEAD_LETTER_EXCHANGE_ARGUMENT),
+            Some(FieldValue::S(exchange)) if exchange.as_ref() == ""
+        ));
+        assert!(matches!(
+            argument(DEAD_LETTER_ROUTING_KEY_ARGUMENT),
+            Some(FieldValue::S(routing_key)) if routing_key.as_ref() == PRODUCT_ADDED_TO_CART_QUEUE_NAME
+        ));
+    }
+
+    #[test]
+    fn event_routing_table_rejects_unknown_event_types() {
+        // Act
+        let result = EventRoutingTable::default().with_overrides("ProductDeletedEvent=products");
+
+        // Assert
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_incoming_event_accepts_enveloped_events() {
+        // Arrange
+        let envelope = EventEnvelope::from_parts(
+            String::from("message-1"),
+            1000,
+            Some(String::from("correlation-1")),
+            Event::ProductRemovedFromCartEvent {
+                product_id: String::from("1"),
+                cart_id: Some(String::from("cart")),
+                quantity: 2,
+            },
+        );
+        let raw_event = serde_json::to_string(&envelope).unwrap();
+
+        // Act
+        let result = parse_incoming_event(&raw_event);
+
+        // Assert
+        let parsed_envelope = result.unwrap();
+        assert_eq!(parsed_envelope.id, "message-1");
+        assert_eq!(parsed_envelope.correlation_id, "correlation-1");
+        assert_eq!(parsed_envelope.schema_version, EVENT_SCHEMA_VERSION);
+        assert_eq!(parsed_envelope.event_type, "ProductRemovedFromCartEvent");
+        assert!(matches!(
+            parsed_envelope.event,
+            Event::ProductRemovedFromCartEvent { quantity: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn parse_incoming_event_wraps_legacy_bare_events() {
+        // Arrange
+        let raw_event = r#"{"ProductRemovedFromCartEvent":{"product_id":"1"}}"#;
+
+        // Act
+        let result = parse_incoming_event(raw_event);
+
+        // Assert
+        let parsed_envelope = result.unwrap();
+        assert_eq!(parsed_envelope.correlation_id, parsed_envelope.id);
+        assert_eq!(parsed_envelope.producer, EVENT_PRODUCER);
+        assert!(matches!(
+            parsed_envelope.event,
+            Event::ProductRemovedFromCartEvent { quantity: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn delivery_outcome_retries_transient_failures_and_dead_letters_invalid_ones() {
+        // Act
+        let infrastructure: DeliveryOutcome =
+            Err::<(), DomainError>(DomainError::Infrastructure(String::from("down"))).into();
+        let validation: DeliveryOutcome =
+            Err::<(), DomainError>(DomainError::Validation(String::from("bad"))).into();
+        let insufficient_stock: DeliveryOutcome =
+            Err::<(), DomainError>(DomainError::InsufficientStock(String::from("short"))).into();
+
+        // Assert
+        assert_eq!(infrastructure, DeliveryOutcome::Retry(String::from("down")));
+        assert_eq!(validation, DeliveryOutcome::DeadLetter(String::from("bad")));
+        assert_eq!(insufficient_stock, DeliveryOutcome::Handled);
+    }
+
+    #[test]
+    fn retry_count_reads_header_and_defaults_to_zero() {
+        // Arrange
+        let mut headers = FieldTable::new();
+        headers.insert(RETRY_COUNT_HEADER.try_into().unwrap(), FieldValue::I(2));
+        let mut retried_properties = BasicProperties::default();
+        retried_properties.with_headers(headers);
+
+        // Act
+        let first_delivery = retry_count(&BasicProperties::default());
+        let retried_delivery = retry_count(&retried_properties);
+
+        // Assert
+        assert_eq!(first_delivery, 0);
+        assert_eq!(retried_delivery, 2);
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_the_maximum() {
+        // Act
+        let delays: Vec<Duration> = [0, 1, 2, 20].into_iter().map(reconnect_delay).collect();
+
+        // Assert
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(500),
+                Duration::from_millis(1000),
+                Duration::from_millis(2000),
+                RECONNECT_MAX_DELAY,
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_confirms_settle_multiple_acks_up_to_the_delivery_tag() {
+        // Arrange
+        let mut pending_confirms = PendingConfirms::default();
+        let mut first = pending_confirms.register();
+        let mut second = pending_confirms.register();
+        let mut third = pending_confirms.register();
+
+        // Act
+        pending_confirms.settle(2, true, true);
+        pending_confirms.settle(3, false, false);
+
+        // Assert
+        assert_eq!(first.try_recv(), Ok(true));
+        assert_eq!(second.try_recv(), Ok(true));
+        assert_eq!(third.try_recv(), Ok(false));
+        assert!(pending_confirms.waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_records_published_events() {
+        // Arrange
+        let message_broker = InMemoryMessageBroker::new(EventRoutingTable::default(), 3);
+        let product_created_event = Event::ProductCreatedEvent {
+            id: String::from("1"),
+            name: String::from("laptop"),
+            price: 1.0,
+        };
+
+        // Act
+        let result = message_broker
+            .publish_envelope(&EventEnvelope::new(product_created_event))
+            .await;
+
+        // Assert
+        assert!(result.is_ok());
+        let published_events = message_broker.published_events();
+        assert_eq!(published_events.len(), 1);
+        assert_eq!(published_events[0].event_type, "ProductCreatedEvent");
+        assert!(message_broker.dead_letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_only_queues_events_with_a_registered_consumer() {
+        // Arrange
+        let message_broker = InMemoryMessageBroker::new(EventRoutingTable::default(), 3);
+        message_broker
+            .sender_for(PRODUCT_ADDED_TO_CART_QUEUE_NAME)
+            .await;
+        let product_added_to_cart_event = Event::ProductAddedToCartEvent {
+            product_id: String::from("1"),
+            cart_id: Some(String::from("cart")),
+            quantity: 1,
+        };
+        let product_created_event = Event::ProductCreatedEvent {
+            id: String::from("1"),
+            name: String::from("laptop"),
+            price: 1.0,
+        };
+
+        // Act
+        message_broker
+            .publish_envelope(&EventEnvelope::new(product_added_to_cart_event))
+            .await
+            .unwrap();
+        message_broker
+            .publish_envelope(&EventEnvelope::new(product_created_event))
+            .await
+            .unwrap();
+
+        // Assert
+        let mut queues = message_broker.queues.lock().await;
+        assert!(!queues.contains_key(PRODUCT_CREATED_EXCHANGE_NAME));
+        let cart_queue = queues.get_mut(PRODUCT_ADDED_TO_CART_QUEUE_NAME).unwrap();
+        assert!(cart_queue.receiver.as_mut().unwrap().try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_keeps_only_the_most_recent_published_events() {
+        // Arrange
+        let message_broker = InMemoryMessageBroker::new(EventRoutingTable::default(), 3);
+        let product_created_event = |id: usize| Event::ProductCreatedEvent {
+            id: id.to_string(),
+            name: String::from("laptop"),
+            price: 1.0,
+        };
+
+        // Act
+        for id in 0..=IN_MEMORY_PUBLISHED_EVENTS_CAPACITY {
+            message_broker
+                .publish_envelope(&EventEnvelope::new(product_created_event(id)))
+                .await
+                .unwrap();
+        }
+
+        // Assert
+        let published_events = message_broker.published_events();
+        assert_eq!(published_events.len(), IN_MEMORY_PUBLISHED_EVENTS_CAPACITY);
+        assert!(matches!(
+            &published_events[0].event,
+            Event::ProductCreatedEvent { id, .. } if id == "1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_dead_letters_once_retries_are_used_up() {
+        // Arrange
+        let message_broker = InMemoryMessageBroker::new(EventRoutingTable::default(), 1);
+        let delivery = InMemoryDelivery {
+            basic_properties: with_retry_count(BasicProperties::default(), 1),
+            content: b"{}".to_vec(),
+        };
+
+        // Act
+        message_broker
+            .settle(
+                PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+                delivery,
+                DeliveryOutcome::Retry(String::from("down")),
+            )
+            .await;
+
+        // Assert
+        let dead_letters = message_broker.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].reason, "down");
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_holds_back_retried_messages() {
+        // Arrange
+        let message_broker = InMemoryMessageBroker::new(EventRoutingTable::default(), 3);
+        message_broker
+            .sender_for(PRODUCT_ADDED_TO_CART_QUEUE_NAME)
+            .await;
+        let mut receiver = message_broker
+            .queues
+            .lock()
+            .await
+            .get_mut(PRODUCT_ADDED_TO_CART_QUEUE_NAME)
+            .and_then(|queue| queue.receiver.take())
+            .unwrap();
+        let delivery = InMemoryDelivery {
+            basic_properties: BasicProperties::default(),
+            content: b"{}".to_vec(),
+        };
+
+        // Act
+        message_broker
+            .settle(
+                PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+                delivery,
+                DeliveryOutcome::Retry(String::from("down")),
+            )
+            .await;
+        tokio::task::yield_now().await;
+
+        // Assert
+        assert!(receiver.try_recv().is_err());
+        assert!(message_broker.dead_letters().is_empty());
+    }
+
+    struct StubEventHandler;
+
+    #[async_trait]
+    impl EventHandler for StubEventHandler {
+        fn event_types(&self) -> &'static [&'static str] {
+            &["ProductAddedToCartEvent"]
+        }
+
+        async fn handle(&self, _event: Event, message_id: String) -> DeliveryOutcome {
+            DeliveryOutcome::Retry(message_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_delivery_dead_letters_unsupported_event_types() {
+        // Arrange
+        let registration =
+            ConsumerRegistration::new(PRODUCT_ADDED_TO_CART_QUEUE_NAME, Arc::new(StubEventHandler));
+        let raw_event =
+            r#"{"ProductRemovedFromCartEvent":{"product_id":"1","cart_id":"2","quantity":1}}"#;
+
+        // Act
+        let outcome = dispatch_delivery(
+            &registration,
+            raw_event.as_bytes(),
+            &BasicProperties::default(),
+        )
+        .await;
+
+        // Assert
+        assert!(matches!(outcome, DeliveryOutcome::DeadLetter(_)));
+    }
+
+    #[tokio::test]
+    async fn dispatch_delivery_passes_the_amqp_message_id_to_the_handler() {
+        // Arrange
+        let registration =
+            ConsumerRegistration::new(PRODUCT_ADDED_TO_CART_QUEUE_NAME, Arc::new(StubEventHandler));
+        let raw_event =
+            r#"{"ProductAddedToCartEvent":{"product_id":"1","cart_id":"2","quantity":1}}"#;
+        let basic_properties = BasicProperties::default()
+            .with_message_id("message-1")
+            .finish();
+
+        // Act
+        let outcome =
+            dispatch_delivery(&registration, raw_event.as_bytes(), &basic_properties).await;
+
+        // Assert
+        assert!(matches!(outcome, DeliveryOutcome::Retry(message_id) if message_id == "message-1"));
+    }
+
+    #[test]
+    fn handler_registry_replaces_registrations_for_the_same_queue() {
+        // Arrange
+        let registry = HandlerRegistry::new().register(ConsumerRegistration::new(
+            PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+            Arc::new(StubEventHandler),
+        ));
+
+        // Act
+        let registry = registry.register(
+            ConsumerRegistration::new(PRODUCT_ADDED_TO_CART_QUEUE_NAME, Arc::new(StubEventHandler))
+                .with_prefetch_count(1)
+                .with_max_concurrency(0),
+        );
+
+        // Assert
+        let registrations = registry.registrations();
+        assert_eq!(registrations.len(), 1);
+        assert_eq!(registrations[0].prefetch_count, 1);
+        assert_eq!(registrations[0].max_concurrency, 1);
+        assert_eq!(
+            registrations[0].consumer_tag,
+            "eshop-product-service.product.added.to.cart"
+        );
+    }
+
+    // Holds every delivery until `barrier` has as many deliveries in flight at once
+    struct BarrierEventHandler {
+        barrier: tokio::sync::Barrier,
+        handled: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl EventHandler for BarrierEventHandler {
+        fn event_types(&self) -> &'static [&'static str] {
+            &["ProductAddedToCartEvent"]
+        }
+
+        async fn handle(&self, _event: Event, _message_id: String) -> DeliveryOutcome {
+            self.barrier.wait().await;
+            self.handled.fetch_add(1, Ordering::SeqCst);
+            DeliveryOutcome::Handled
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_broker_handles_deliveries_concurrently_up_to_max_concurrency() {
+        // Arrange
+        let message_broker = Arc::new(InMemoryMessageBroker::new(EventRoutingTable::default(), 3));
+        let handler = Arc::new(BarrierEventHandler {
+            barrier: tokio::sync::Barrier::new(2),
+            handled: AtomicUsize::new(0),
+        });
+        let registry = HandlerRegistry::new().register(
+            ConsumerRegistration::new(PRODUCT_ADDED_TO_CART_QUEUE_NAME, handler.clone())
+                .with_max_concurrency(2),
+        );
+        let message_broker_for_consumers = message_broker.clone();
+        let consumers = tokio::spawn(async move {
+            message_broker_for_consumers.subscribe(registry).await;
+        });
+        while !message_broker
+            .queues
+            .lock()
+            .await
+            .contains_key(PRODUCT_ADDED_TO_CART_QUEUE_NAME)
+        {
+            tokio::task::yield_now().await;
         }
+
+        // Act
+        for cart_id in ["first", "second"] {
+            message_broker
+                .publish_envelope(&EventEnvelope::new(Event::ProductAddedToCartEvent {
+                    product_id: String::from("1"),
+                    cart_id: Some(cart_id.to_string()),
+                    quantity: 1,
+                }))
+                .await
+                .unwrap();
+        }
+        let all_handled = tokio::time::timeout(Duration::from_secs(1), async {
+            while handler.handled.load(Ordering::SeqCst) < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await;
+        consumers.abort();
+
+        // Assert
+        assert!(all_handled.is_ok());
     }
 }
@@ -0,0 +1,252 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{event, Level};
+
+use crate::clock::current_utc_millis;
+use crate::cqrs::{
+    CommandHandler, PurgeDeletedProductsCommand, PurgeDeletedProductsCommandHandler,
+    ReleaseExpiredReservationsCommand, ReleaseExpiredReservationsCommandHandler,
+};
+use crate::events::{EventEnvelope, MessageBroker};
+use crate::repositories::OutboxRepository;
+
+pub async fn purge_deleted_products(
+    handler: Arc<PurgeDeletedProductsCommandHandler>,
+    retention: Duration,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let purge_deleted_products_command = PurgeDeletedProductsCommand {
+            deleted_before_utc: current_utc_millis() - retention.as_millis() as i64,
+        };
+
+        if let Err(e) = handler.handle(&purge_deleted_products_command).await {
+            event!(Level::WARN, "Failed to purge deleted products: {}", e);
+        }
+    }
+}
+
+pub async fn release_expired_reservations(
+    handler: Arc<ReleaseExpiredReservationsCommandHandler>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let release_expired_reservations_command = ReleaseExpiredReservationsCommand {
+            expired_before_utc: current_utc_millis(),
+        };
+
+        if let Err(e) = handler.handle(&release_expired_reservations_command).await {
+            event!(Level::WARN, "Failed to release expired reservations: {}", e);
+        }
+    }
+}
+
+const OUTBOX_RELAY_BATCH_SIZE: i64 = 100;
+const OUTBOX_RETRY_BASE_DELAY_MILLIS: i64 = 1000;
+const OUTBOX_RETRY_MAX_DELAY_MILLIS: i64 = 5 * 60 * 1000;
+
+pub async fn relay_outbox_messages(
+    outbox_repository: Arc<dyn OutboxRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    interval: Duration,
+    max_attempts: u32,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        relay_pending_outbox_messages(&outbox_repository, &message_broker, max_attempts).await;
+    }
+}
+
+// Publishes one batch of due outbox messages, marking each delivered or scheduling its retry
+async fn relay_pending_outbox_messages(
+    outbox_repository: &Arc<dyn OutboxRepository + Send + Sync>,
+    message_broker: &Arc<dyn MessageBroker + Send + Sync>,
+    max_attempts: u32,
+) {
+    let pending_messages = match outbox_repository
+        .read_pending(current_utc_millis(), max_attempts, OUTBOX_RELAY_BATCH_SIZE)
+        .await
+    {
+        Ok(messages) => messages,
+        Err(e) => {
+            event!(Level::WARN, "Failed to read pending outbox messages: {}", e);
+            return;
+        }
+    };
+
+    for message in pending_messages {
+        // The outbox id doubles as the message id so redeliveries can be recognised downstream
+        let envelope = EventEnvelope::from_parts(
+            message.id.clone(),
+            message.created_at_utc,
+            message.correlation_id.clone(),
+            message.event.clone(),
+        );
+
+        match message_broker.publish_envelope(&envelope).await {
+            Ok(()) => {
+                if let Err(e) = outbox_repository
+                    .mark_delivered(&message.id, current_utc_millis())
+                    .await
+                {
+                    event!(
+                        Level::WARN,
+                        "Failed to mark outbox message {} delivered: {}",
+                        message.id,
+                        e
+                    );
+                }
+            }
+            Err(publish_error) => {
+                let attempt = message.attempts + 1;
+                let delay = OUTBOX_RETRY_BASE_DELAY_MILLIS
+                    .saturating_mul(2i64.saturating_pow(attempt))
+                    .min(OUTBOX_RETRY_MAX_DELAY_MILLIS);
+
+                if attempt >= max_attempts {
+                    event!(
+                        Level::ERROR,
+                        "Giving up on outbox message {} after {} attempts: {}",
+                        message.id,
+                        attempt,
+                        publish_error
+                    );
+                } else {
+                    event!(
+                        Level::WARN,
+                        "Failed to publish outbox message {} (attempt {}): {}",
+                        message.id,
+                        attempt,
+                        publish_error
+                    );
+                }
+
+                if let Err(e) = outbox_repository
+                    .record_failure(&message.id, current_utc_millis() + delay, &publish_error)
+                    .await
+                {
+                    event!(
+                        Level::WARN,
+                        "Failed to record outbox delivery failure for {}: {}",
+                        message.id,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::domain::OutboxMessage;
+    use crate::events::{Event, EventRoutingTable, HandlerRegistry, InMemoryMessageBroker};
+    use crate::repositories::InMemoryOutboxRepository;
+    use crate::test_support::client_session;
+
+    struct UnavailableMessageBroker;
+
+    #[async_trait]
+    impl MessageBroker for UnavailableMessageBroker {
+        async fn publish_envelope(&self, _: &EventEnvelope) -> Result<(), String> {
+            Err(String::from("broker unavailable"))
+        }
+
+        async fn subscribe(&self, _: HandlerRegistry) {}
+    }
+
+    async fn outbox_with_pending_message() -> Arc<InMemoryOutboxRepository> {
+        let outbox_repository = Arc::new(InMemoryOutboxRepository::new());
+        outbox_repository
+            .create(
+                OutboxMessage {
+                    id: String::from("1"),
+                    event: Event::ProductCreatedEvent {
+                        id: String::from("1"),
+                        name: String::from("laptop"),
+                        price: 10.0,
+                    },
+                    correlation_id: None,
+                    created_at_utc: 0,
+                    delivered_at_utc: None,
+                    attempts: 0,
+                    next_attempt_at_utc: 0,
+                    last_error: None,
+                },
+                client_session().await,
+            )
+            .await
+            .unwrap();
+
+        outbox_repository
+    }
+
+    #[tokio::test]
+    async fn relay_marks_published_messages_delivered() {
+        // Arrange
+        let outbox_repository = outbox_with_pending_message().await;
+        let message_broker = Arc::new(InMemoryMessageBroker::new(EventRoutingTable::default(), 3));
+
+        // Act
+        relay_pending_outbox_messages(
+            &(outbox_repository.clone() as _),
+            &(message_broker.clone() as _),
+            3,
+        )
+        .await;
+
+        // Assert
+        assert_eq!(message_broker.published_events()[0].id, "1");
+        assert!(outbox_repository
+            .read_pending(i64::MAX, 3, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn relay_schedules_a_retry_when_publishing_fails() {
+        // Arrange
+        let outbox_repository = outbox_with_pending_message().await;
+
+        // Act
+        relay_pending_outbox_messages(
+            &(outbox_repository.clone() as _),
+            &(Arc::new(UnavailableMessageBroker) as _),
+            3,
+        )
+        .await;
+
+        // Assert
+        let pending_messages = outbox_repository
+            .read_pending(i64::MAX, 3, 10)
+            .await
+            .unwrap();
+        assert_eq!(pending_messages[0].attempts, 1);
+        assert!(pending_messages[0].next_attempt_at_utc > current_utc_millis());
+        assert_eq!(
+            pending_messages[0].last_error.as_deref(),
+            Some("broker unavailable")
+        );
+        assert!(outbox_repository
+            .read_pending(current_utc_millis(), 3, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,87 +1,189 @@
 // define modules in crate
 mod auth;
+mod clock;
 mod cqrs;
 mod domain;
 mod dtos;
 mod events;
+mod jobs;
 mod metrics;
 mod repositories;
 mod routes;
+mod search;
 mod state;
+#[cfg(test)]
+mod test_support;
 mod uow;
 
 use axum::{
     middleware::from_fn_with_state,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Router,
 };
 use axum_prometheus::PrometheusMetricLayer;
 use cqrs::{
-    CreateProductCommandHandler, DecrementProductInventoryCommandHandler, GetProductsQueryHandler,
-    IncrementProdcuctInventoryCommandHandler, ModifyProductInventoryCommandHandler,
+    BatchGetProductsQueryHandler, CommitOrderedInventoryCommandHandler,
+    CreateProductCommandHandler, DecrementProductInventoryCommandHandler,
+    DeleteProductCommandHandler, GetProductQueryHandler, IncrementProdcuctInventoryCommandHandler,
+    ListProductsQueryHandler, ModifyProductInventoryCommandHandler,
+    PurgeDeletedProductsCommandHandler, ReleaseExpiredReservationsCommandHandler,
+    RestockOrderedInventoryCommandHandler, RestoreProductCommandHandler,
+    SearchProductsQueryHandler, UpdateProductCommandHandler,
 };
 use dotenv::dotenv;
-use events::{MessageBroker, RabbitMqInitializationInfo, RabbitMqMessageBroker};
+use events::{
+    ConsumerRegistration, EventRoutingTable, HandlerRegistry, InMemoryMessageBroker, MessageBroker,
+    OrderPlacedEventHandler, OrderRestockEventHandler, ProductAddedToCartEventHandler,
+    ProductRemoveFromCartEventHandler, RabbitMqInitializationInfo, RabbitMqMessageBroker,
+};
 use mongodb::Client;
-use repositories::{MongoDbInitializationInfo, MongoDbProductRepository};
+use repositories::{
+    MongoDbInitializationInfo, MongoDbOutboxRepository, MongoDbProcessedMessageRepository,
+    MongoDbProductRepository, MongoDbReservationRepository,
+};
 use routes::*;
 use state::AppState;
 use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
 use crate::uow::ProductUnitOfWork;
 
+// Optional settings fall back to their default when unset; a value that does not parse stops the
+// service at startup instead of running with a setting nobody asked for
+fn env_var_or<T>(name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| panic!("{} has an invalid value {:?}: {}", name, value, e)),
+        Err(_) => default,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
     let info = MongoDbInitializationInfo {
-        uri: String::from(env::var("MONGODB_URI").unwrap()),
-        database: String::from(env::var("MONGODB_DB").unwrap()),
-        collection: String::from(env::var("MONGODB_COLLECTION").unwrap()),
+        uri: env::var("MONGODB_URI").unwrap(),
+        database: env::var("MONGODB_DB").unwrap(),
+        collection: env::var("MONGODB_COLLECTION").unwrap(),
+        reservation_collection: env_var_or(
+            "MONGODB_RESERVATION_COLLECTION",
+            String::from("reservations"),
+        ),
+        outbox_collection: env_var_or("MONGODB_OUTBOX_COLLECTION", String::from("outbox")),
+        processed_message_collection: env_var_or(
+            "MONGODB_PROCESSED_MESSAGE_COLLECTION",
+            String::from("processed_messages"),
+        ),
     };
 
     let client: Client = Client::with_uri_str(&info.uri).await.unwrap();
 
     let product_repository = Arc::new(MongoDbProductRepository::new(&info, &client).await);
+    let reservation_repository = Arc::new(MongoDbReservationRepository::new(&info, &client).await);
+    let outbox_repository = Arc::new(MongoDbOutboxRepository::new(&info, &client).await);
+    let processed_message_repository =
+        Arc::new(MongoDbProcessedMessageRepository::new(&info, &client).await);
 
-    let client_session = Arc::new(Mutex::new(client.start_session().await.unwrap()));
+    // Routes default to one exchange per event type and can be overridden without a rebuild
+    let event_routing_table = EventRoutingTable::default()
+        .with_overrides(&env::var("EVENT_ROUTES").unwrap_or_default())
+        .unwrap();
 
-    let message_broker = Arc::new(
-        RabbitMqMessageBroker::new(RabbitMqInitializationInfo::new(
-            String::from(env::var("RABBITMQ_URI").unwrap()),
-            env::var("RABBITMQ_PORT").unwrap().parse().unwrap(),
-            String::from(env::var("RABBITMQ_USER").unwrap()),
-            String::from(env::var("RABBITMQ_PASS").unwrap()),
-        ))
-        .await
-        .unwrap(),
-    );
+    let consumer_max_retries = env_var_or("RABBITMQ_CONSUMER_MAX_RETRIES", 5);
+
+    // MESSAGE_BROKER=in_memory runs the service and its event flows without RabbitMQ
+    let (message_broker, message_broker_health): (Arc<dyn MessageBroker + Send + Sync>, _) =
+        match env::var("MESSAGE_BROKER").unwrap_or_default().as_str() {
+            "in_memory" => {
+                let in_memory_message_broker = Arc::new(InMemoryMessageBroker::new(
+                    event_routing_table,
+                    consumer_max_retries,
+                ));
+                let broker_health = in_memory_message_broker.health();
+                (in_memory_message_broker, broker_health)
+            }
+            _ => {
+                let rabbit_mq_message_broker = Arc::new(
+                    RabbitMqMessageBroker::new(
+                        RabbitMqInitializationInfo::new(
+                            env::var("RABBITMQ_URI").unwrap(),
+                            env::var("RABBITMQ_PORT").unwrap().parse().unwrap(),
+                            env::var("RABBITMQ_USER").unwrap(),
+                            env::var("RABBITMQ_PASS").unwrap(),
+                            consumer_max_retries,
+                        ),
+                        event_routing_table,
+                    )
+                    .await
+                    .unwrap(),
+                );
+                let broker_health = rabbit_mq_message_broker.health();
+
+                let message_broker_for_supervisor = rabbit_mq_message_broker.clone();
+                tokio::spawn(async move {
+                    message_broker_for_supervisor.supervise_connection().await;
+                });
+
+                (rabbit_mq_message_broker, broker_health)
+            }
+        };
     let uow = Arc::new(ProductUnitOfWork::new(
         product_repository.clone(),
-        message_broker.clone(),
-        client_session,
+        reservation_repository.clone(),
+        processed_message_repository,
+        outbox_repository.clone(),
+        client.clone(),
     ));
     let create_product_command_handler = Arc::new(CreateProductCommandHandler::new(uow.clone()));
-    let get_products_query_handler = Arc::new(GetProductsQueryHandler::new(uow.clone()));
+    let update_product_command_handler = Arc::new(UpdateProductCommandHandler::new(uow.clone()));
+    let delete_product_command_handler = Arc::new(DeleteProductCommandHandler::new(uow.clone()));
+    let restore_product_command_handler = Arc::new(RestoreProductCommandHandler::new(uow.clone()));
+    let purge_deleted_products_command_handler =
+        Arc::new(PurgeDeletedProductsCommandHandler::new(uow.clone()));
+    let get_product_query_handler = Arc::new(GetProductQueryHandler::new(uow.clone()));
+    let batch_get_products_query_handler = Arc::new(BatchGetProductsQueryHandler::new(uow.clone()));
+    let list_products_query_handler = Arc::new(ListProductsQueryHandler::new(uow.clone()));
+    let search_products_query_handler = Arc::new(SearchProductsQueryHandler::new(uow.clone()));
     let modify_product_inventory_command_handler =
         Arc::new(ModifyProductInventoryCommandHandler::new(uow.clone()));
     let decrement_product_inventory_command_handler =
         Arc::new(DecrementProductInventoryCommandHandler::new(uow.clone()));
-    let increment_product_inventory_command_handler =
-        Arc::new(IncrementProdcuctInventoryCommandHandler::new(uow.clone()));
+    let reservation_ttl = Duration::from_secs(env_var_or("RESERVATION_TTL_SECONDS", 30 * 60));
+    let increment_product_inventory_command_handler = Arc::new(
+        IncrementProdcuctInventoryCommandHandler::new(uow.clone(), reservation_ttl),
+    );
+    let commit_ordered_inventory_command_handler =
+        Arc::new(CommitOrderedInventoryCommandHandler::new(uow.clone()));
+    let restock_ordered_inventory_command_handler =
+        Arc::new(RestockOrderedInventoryCommandHandler::new(uow.clone()));
+    let release_expired_reservations_command_handler =
+        Arc::new(ReleaseExpiredReservationsCommandHandler::new(uow.clone()));
 
     let state = Arc::new(AppState {
-        create_product_command_handler: create_product_command_handler,
-        get_products_query_handler: get_products_query_handler,
-        modify_product_inventory_command_handler: modify_product_inventory_command_handler,
-        decrement_product_inventory_command_handler: decrement_product_inventory_command_handler,
-        increment_product_inventory_command_handler: increment_product_inventory_command_handler,
-        auth0_domain: String::from(env::var("AUTH0_DOMAIN").unwrap()),
-        auth0_audience: String::from(env::var("AUTH0_AUDIENCE").unwrap()),
+        create_product_command_handler,
+        update_product_command_handler,
+        delete_product_command_handler,
+        restore_product_command_handler,
+        get_product_query_handler,
+        batch_get_products_query_handler,
+        list_products_query_handler,
+        search_products_query_handler,
+        modify_product_inventory_command_handler,
+        auth0_domain: env::var("AUTH0_DOMAIN").unwrap(),
+        auth0_audience: env::var("AUTH0_AUDIENCE").unwrap(),
+        auth0_admin_scope: env_var_or("AUTH0_ADMIN_SCOPE", String::from("admin:products")),
+        broker_health: message_broker_health,
     });
 
     tracing_subscriber::fmt()
@@ -92,7 +194,7 @@ async fn main() {
         .with_file(true)
         .with_line_number(true)
         .with_current_span(true)
-        .with_writer(std::fs::File::create(String::from(env::var("LOG_PATH").unwrap())).unwrap())
+        .with_writer(std::fs::File::create(env::var("LOG_PATH").unwrap()).unwrap())
         .init();
 
     let (prometheus_layer, metrics_handle) = PrometheusMetricLayer::pair();
@@ -102,23 +204,96 @@ async fn main() {
             .await
             .unwrap();
 
-    let state_clone_for_background_jobs = state.clone();
-    let message_broke_for_background_jobs = message_broker.clone();
+    // Every command runs in a session of its own, so deliveries on a queue can be handled
+    // concurrently; prefetch controls how far ahead of the handlers the broker pushes
+    let consumer_prefetch_count = env_var_or(
+        "RABBITMQ_CONSUMER_PREFETCH_COUNT",
+        events::DEFAULT_PREFETCH_COUNT,
+    );
+    let consumer_max_concurrency = env_var_or(
+        "RABBITMQ_CONSUMER_MAX_CONCURRENCY",
+        events::DEFAULT_MAX_CONCURRENCY,
+    );
+    let consumer_registration = |queue_name, handler| {
+        ConsumerRegistration::new(queue_name, handler)
+            .with_prefetch_count(consumer_prefetch_count)
+            .with_max_concurrency(consumer_max_concurrency)
+    };
+    let order_restock_event_handler = Arc::new(OrderRestockEventHandler::new(
+        restock_ordered_inventory_command_handler,
+    ));
+    let handler_registry = HandlerRegistry::new()
+        .register(consumer_registration(
+            events::PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+            Arc::new(ProductAddedToCartEventHandler::new(
+                increment_product_inventory_command_handler,
+            )),
+        ))
+        .register(consumer_registration(
+            events::PRODUCT_REMOVED_FROM_CART_QUEUE_NAME,
+            Arc::new(ProductRemoveFromCartEventHandler::new(
+                decrement_product_inventory_command_handler,
+            )),
+        ))
+        .register(consumer_registration(
+            events::ORDER_PLACED_QUEUE_NAME,
+            Arc::new(OrderPlacedEventHandler::new(
+                commit_ordered_inventory_command_handler,
+            )),
+        ))
+        .register(consumer_registration(
+            events::ORDER_CANCELLED_QUEUE_NAME,
+            order_restock_event_handler.clone(),
+        ))
+        .register(consumer_registration(
+            events::ORDER_RETURNED_QUEUE_NAME,
+            order_restock_event_handler,
+        ));
+
+    let message_broker_for_consumers = message_broker.clone();
     tokio::spawn(async move {
-        message_broke_for_background_jobs
-            .consume(
-                events::PRODUCT_ADDED_TO_CART_QUEUE_NAME,
-                state_clone_for_background_jobs,
-            )
+        message_broker_for_consumers
+            .subscribe(handler_registry)
             .await;
     });
 
-    let state_clone_2 = state.clone();
-    let message_broker_clone_2 = message_broker.clone();
+    let outbox_relay_interval =
+        Duration::from_millis(env_var_or("OUTBOX_RELAY_INTERVAL_MILLISECONDS", 500));
+    let outbox_max_delivery_attempts = env_var_or("OUTBOX_MAX_DELIVERY_ATTEMPTS", 10);
+    let message_broker_for_outbox_relay = message_broker.clone();
     tokio::spawn(async move {
-        message_broker_clone_2
-            .consume(events::PRODUCT_REMOVED_FROM_CART_QUEUE_NAME, state_clone_2)
-            .await;
+        jobs::relay_outbox_messages(
+            outbox_repository,
+            message_broker_for_outbox_relay,
+            outbox_relay_interval,
+            outbox_max_delivery_attempts,
+        )
+        .await;
+    });
+
+    let reservation_sweep_interval =
+        Duration::from_secs(env_var_or("RESERVATION_SWEEP_INTERVAL_SECONDS", 60));
+    tokio::spawn(async move {
+        jobs::release_expired_reservations(
+            release_expired_reservations_command_handler,
+            reservation_sweep_interval,
+        )
+        .await;
+    });
+
+    let deleted_product_retention =
+        Duration::from_secs(env_var_or("DELETED_PRODUCT_RETENTION_HOURS", 30 * 24) * 60 * 60);
+    let deleted_product_purge_interval = Duration::from_secs(env_var_or(
+        "DELETED_PRODUCT_PURGE_INTERVAL_SECONDS",
+        60 * 60,
+    ));
+    tokio::spawn(async move {
+        jobs::purge_deleted_products(
+            purge_deleted_products_command_handler,
+            deleted_product_retention,
+            deleted_product_purge_interval,
+        )
+        .await;
     });
 
     axum::serve(
@@ -126,9 +301,64 @@ async fn main() {
         Router::new()
             .route("/", get(index))
             .route("/metrics", get(|| async move { metrics_handle.render() }))
+            .route("/health/broker", get(broker_health))
+            .route(
+                "/products/{id}",
+                get(get_product)
+                    .put(update_product)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
             .route(
                 "/products/{id}",
-                get(get_products).route_layer(from_fn_with_state(
+                delete(delete_product)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::admin_authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/products/{id}/restore",
+                post(restore_product)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::admin_authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/products/by-slug/{slug}",
+                get(get_product_by_slug).route_layer(from_fn_with_state(
+                    state.clone(),
+                    auth::authentication_middleware,
+                )),
+            )
+            .route(
+                "/products/by-sku/{sku}",
+                get(get_product_by_sku).route_layer(from_fn_with_state(
+                    state.clone(),
+                    auth::authentication_middleware,
+                )),
+            )
+            .route(
+                "/products:batchGet",
+                post(batch_get_products).route_layer(from_fn_with_state(
+                    state.clone(),
+                    auth::authentication_middleware,
+                )),
+            )
+            .route(
+                "/products/search",
+                get(search_products).route_layer(from_fn_with_state(
                     state.clone(),
                     auth::authentication_middleware,
                 )),
@@ -136,7 +366,7 @@ async fn main() {
             .route(
                 "/products",
                 post(create_product)
-                    .get(get_all_products)
+                    .get(list_products)
                     .route_layer(from_fn_with_state(
                         state.clone(),
                         auth::authentication_middleware,
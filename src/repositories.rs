@@ -1,15 +1,48 @@
-use crate::domain::Product;
+use crate::{
+    domain::{
+        price_facet_bucket, price_facet_value, stock_facet_value, DomainError, FacetBucket,
+        OutboxMessage, ProcessedMessage, Product, ProductFacets, ProductFilter, ProductKey,
+        ProductPage, ProductPageRequest, ProductSearchHit, ProductSearchPage, ProductSearchRequest,
+        ProductSortField, Reservation, SortOrder, PRICE_FACET_BOUNDARIES,
+    },
+    search::{relevance_score, tokenize, DESCRIPTION_WEIGHT, NAME_WEIGHT},
+};
 use async_trait::async_trait;
 use futures_util::TryStreamExt;
-use mongodb::{bson::doc, Client, ClientSession, Collection};
-use std::{collections::HashMap, sync::Arc};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR},
+    options::{IndexOptions, ReturnDocument},
+    Client, ClientSession, Collection, IndexModel,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+// A failed write aborts the server-side transaction; when Mongo labels the failure transient
+// the unit of work starts the transaction over rather than failing the command
+pub fn transaction_error(context: &str, e: MongoError) -> DomainError {
+    let message = format!("{}: {}", context, e);
+
+    match e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+        true => DomainError::Transient(message),
+        false => DomainError::Infrastructure(message),
+    }
+}
 
 #[derive(Debug)]
 pub struct MongoDbInitializationInfo {
     pub uri: String,
     pub database: String,
     pub collection: String,
+    pub reservation_collection: String,
+    pub outbox_collection: String,
+    pub processed_message_collection: String,
 }
 
 #[async_trait]
@@ -19,29 +52,81 @@ pub trait ProductRepository {
         id: String,
         product: Product,
         session: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String>;
-    async fn read<'a>(&self, id: &'a str) -> Result<Product, String>;
-    async fn read_all(&self) -> Result<Vec<Product>, String>;
+    ) -> Result<Product, DomainError>;
+    async fn read<'a>(&self, id: &'a str) -> Result<Product, DomainError>;
+    async fn read_deleted<'a>(&self, id: &'a str) -> Result<Product, DomainError>;
+    /// Returns the live product with the given id, slug or SKU, or `DomainError::NotFound`.
+    async fn read_by_key(&self, key: &ProductKey) -> Result<Product, DomainError>;
+    /// Returns the live products among `ids` in a single lookup; ids without a live product
+    /// are simply missing from the result, in no particular order.
+    async fn read_many(&self, ids: &[String]) -> Result<Vec<Product>, DomainError>;
+    /// Returns one page of live products matching the filter, in the requested order,
+    /// together with the number of products matching across all pages.
+    async fn read_page(&self, request: &ProductPageRequest) -> Result<ProductPage, DomainError>;
+    /// Returns one page of live products matching both the search text and the filter, most
+    /// relevant first, together with the number of matches across all pages.
+    async fn search(
+        &self,
+        request: &ProductSearchRequest,
+    ) -> Result<ProductSearchPage, DomainError>;
+    /// Counts facet buckets over every live product matching the filter and, when given, the
+    /// search text.
+    async fn facets(
+        &self,
+        filter: &ProductFilter,
+        search_text: Option<&str>,
+    ) -> Result<ProductFacets, DomainError>;
+    /// Replaces the product only if its persisted version still matches `product.version`,
+    /// bumping the version on success and returning `DomainError::Conflict` otherwise.
     async fn update(
         &self,
         id: String,
         product: Product,
         session: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String>;
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>);
+    ) -> Result<Product, DomainError>;
+    /// Atomically adds the deltas to the inventory counters, failing with
+    /// `DomainError::InsufficientStock` rather than letting either counter drop below zero
+    /// or letting the available inventory fall below what is reserved.
+    async fn adjust_inventory(
+        &self,
+        id: &str,
+        available_delta: i64,
+        reserved_delta: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Product, DomainError>;
+    /// Permanently removes every product soft-deleted before `deleted_before_utc` and returns
+    /// how many were removed; a product restored in the meantime no longer matches and is kept.
+    async fn delete_all_deleted_before(
+        &self,
+        deleted_before_utc: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<u64, DomainError>;
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 pub struct InMemoryProductRepository {
     products: Arc<Mutex<HashMap<String, Product>>>,
 }
 
+#[allow(dead_code)]
 impl InMemoryProductRepository {
     pub fn new() -> Self {
         InMemoryProductRepository {
             products: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    pub fn with_products(products: Vec<Product>) -> Self {
+        InMemoryProductRepository {
+            products: Arc::new(Mutex::new(
+                products
+                    .into_iter()
+                    .map(|product| (product.id.clone(), product))
+                    .collect(),
+            )),
+        }
+    }
 }
 
 #[async_trait]
@@ -51,54 +136,409 @@ impl ProductRepository for InMemoryProductRepository {
         id: String,
         product: Product,
         _: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String> {
+    ) -> Result<Product, DomainError> {
         let mut lock = self.products.lock().await;
         lock.insert(id.clone(), product.clone());
         match lock.get(id.as_str()) {
             Some(x) => Ok(x.clone()),
-            None => Err(format!("Product with id {} did not exist", id)),
+            None => Err(DomainError::NotFound(format!(
+                "Product with id {} did not exist",
+                id
+            ))),
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Product, String> {
+    async fn read<'a>(&self, id: &'a str) -> Result<Product, DomainError> {
         let lock = self.products.lock().await;
         match lock.get(id) {
-            Some(x) => Ok(x.clone()),
-            None => Err(format!("Product with id {} did not exist", id)),
+            Some(x) if x.deleted_at_utc.is_none() => Ok(x.clone()),
+            _ => Err(DomainError::NotFound(format!(
+                "Product with id {} did not exist",
+                id
+            ))),
         }
     }
 
-    async fn read_all(&self) -> Result<Vec<Product>, String> {
-        let mut products_to_return = Vec::new();
+    async fn read_deleted<'a>(&self, id: &'a str) -> Result<Product, DomainError> {
+        let lock = self.products.lock().await;
+        match lock.get(id) {
+            Some(x) if x.deleted_at_utc.is_some() => Ok(x.clone()),
+            _ => Err(DomainError::NotFound(format!(
+                "Deleted product with id {} did not exist",
+                id
+            ))),
+        }
+    }
+
+    async fn read_by_key(&self, key: &ProductKey) -> Result<Product, DomainError> {
+        let lock = self.products.lock().await;
+
+        lock.values()
+            .filter(|product| product.deleted_at_utc.is_none())
+            .filter(|product| match key {
+                ProductKey::Id(id) => &product.id == id,
+                ProductKey::Slug(slug) => &product.slug == slug,
+                ProductKey::Sku(sku) => product.sku.as_ref() == Some(sku),
+            })
+            .min_by(|left, right| {
+                left.created_at_utc
+                    .cmp(&right.created_at_utc)
+                    .then_with(|| left.id.cmp(&right.id))
+            })
+            .cloned()
+            .ok_or_else(|| DomainError::NotFound(format!("Product with {} did not exist", key)))
+    }
+
+    async fn read_many(&self, ids: &[String]) -> Result<Vec<Product>, DomainError> {
+        let lock = self.products.lock().await;
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| lock.get(id))
+            .filter(|product| product.deleted_at_utc.is_none())
+            .cloned()
+            .collect())
+    }
+
+    async fn read_page(&self, request: &ProductPageRequest) -> Result<ProductPage, DomainError> {
+        let lock = self.products.lock().await;
+
+        let mut matching_products: Vec<Product> = lock
+            .values()
+            .filter(|product| request.filter.matches(product))
+            .cloned()
+            .collect();
+        matching_products.sort_by(|left, right| request.compare(left, right));
+
+        Ok(ProductPage {
+            total_count: matching_products.len() as u64,
+            products: matching_products
+                .into_iter()
+                .skip(request.offset as usize)
+                .take(request.limit as usize)
+                .collect(),
+        })
+    }
+
+    async fn search(
+        &self,
+        request: &ProductSearchRequest,
+    ) -> Result<ProductSearchPage, DomainError> {
+        let terms = tokenize(&request.text);
         let lock = self.products.lock().await;
 
-        for (_, value) in lock.iter() {
-            products_to_return.push(value.clone());
+        let mut hits: Vec<ProductSearchHit> = lock
+            .values()
+            .filter(|product| request.filter.matches(product))
+            .map(|product| ProductSearchHit {
+                product: product.clone(),
+                score: relevance_score(product, &terms),
+            })
+            .filter(|hit| hit.score > 0.0)
+            .collect();
+        hits.sort_by(|left, right| {
+            right
+                .score
+                .total_cmp(&left.score)
+                .then_with(|| left.product.id.cmp(&right.product.id))
+        });
+
+        Ok(ProductSearchPage {
+            total_count: hits.len() as u64,
+            hits: hits
+                .into_iter()
+                .skip(request.offset as usize)
+                .take(request.limit as usize)
+                .collect(),
+        })
+    }
+
+    async fn facets(
+        &self,
+        filter: &ProductFilter,
+        search_text: Option<&str>,
+    ) -> Result<ProductFacets, DomainError> {
+        let terms = search_text.map(tokenize);
+        let lock = self.products.lock().await;
+
+        let mut price = BTreeMap::new();
+        let mut rating = BTreeMap::new();
+        let mut stock = BTreeMap::new();
+        let mut category = HashMap::new();
+        let mut brand = HashMap::new();
+        for product in lock.values().filter(|product| {
+            filter.matches(product)
+                && terms
+                    .as_ref()
+                    .is_none_or(|terms| relevance_score(product, terms) > 0.0)
+        }) {
+            *price
+                .entry(price_facet_bucket(product.price as f64))
+                .or_insert(0) += 1;
+            *rating.entry(product.stars).or_insert(0) += 1;
+            // Keyed on "out of stock" so the in-stock bucket sorts first
+            *stock
+                .entry(product.available_inventory <= product.reserved_inventory)
+                .or_insert(0) += 1;
+            if let Some(value) = &product.category {
+                *category.entry(value.clone()).or_insert(0) += 1;
+            }
+            if let Some(value) = &product.brand {
+                *brand.entry(value.clone()).or_insert(0) += 1;
+            }
         }
 
-        Ok(products_to_return)
+        Ok(ProductFacets {
+            price: price
+                .into_iter()
+                .map(|(bucket, count)| FacetBucket {
+                    value: price_facet_value(bucket),
+                    count,
+                })
+                .collect(),
+            rating: rating
+                .into_iter()
+                .map(|(stars, count)| FacetBucket {
+                    value: stars.to_string(),
+                    count,
+                })
+                .collect(),
+            stock: stock
+                .into_iter()
+                .map(|(out_of_stock, count)| FacetBucket {
+                    value: stock_facet_value(!out_of_stock).to_string(),
+                    count,
+                })
+                .collect(),
+            category: buckets_by_count(category),
+            brand: buckets_by_count(brand),
+        })
     }
 
     async fn update(
         &self,
         id: String,
-        product: Product,
+        mut product: Product,
         _: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String> {
+    ) -> Result<Product, DomainError> {
         let mut lock = self.products.lock().await;
-        lock.insert(id.clone(), product.clone());
         match lock.get(id.as_str()) {
-            Some(x) => Ok(x.clone()),
-            None => Err(format!("Product with id {} did not exist", id)),
+            Some(x) if x.version == product.version => {
+                product.version += 1;
+                lock.insert(id.clone(), product.clone());
+                Ok(product)
+            }
+            Some(x) => Err(DomainError::Conflict(format!(
+                "Product with id {} is at version {} but version {} was expected",
+                id, x.version, product.version
+            ))),
+            None => Err(DomainError::NotFound(format!(
+                "Product with id {} did not exist",
+                id
+            ))),
+        }
+    }
+
+    async fn adjust_inventory(
+        &self,
+        id: &str,
+        available_delta: i64,
+        reserved_delta: i64,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<Product, DomainError> {
+        let mut lock = self.products.lock().await;
+        match lock.get_mut(id) {
+            Some(x) if x.deleted_at_utc.is_none() => {
+                let available_inventory =
+                    u32::try_from(x.available_inventory as i64 + available_delta);
+                let reserved_inventory =
+                    u32::try_from(x.reserved_inventory as i64 + reserved_delta);
+
+                match (available_inventory, reserved_inventory) {
+                    (Ok(available_inventory), Ok(reserved_inventory))
+                        if (reserved_delta <= 0 && available_delta >= 0)
+                            || reserved_inventory <= available_inventory =>
+                    {
+                        x.available_inventory = available_inventory;
+                        x.reserved_inventory = reserved_inventory;
+                        x.updated_at_utc = chrono::Utc::now().timestamp_millis();
+                        x.version += 1;
+                        Ok(x.clone())
+                    }
+                    _ => Err(DomainError::InsufficientStock(format!(
+                        "Inventory for product with id {} cannot go below zero",
+                        id
+                    ))),
+                }
+            }
+            _ => Err(DomainError::NotFound(format!(
+                "Product with id {} did not exist",
+                id
+            ))),
         }
     }
 
-    async fn delete(&self, id: &str, _: Arc<Mutex<ClientSession>>) {
+    async fn delete_all_deleted_before(
+        &self,
+        deleted_before_utc: i64,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<u64, DomainError> {
         let mut lock = self.products.lock().await;
-        lock.remove_entry(id);
+        let count_before = lock.len();
+
+        lock.retain(|_, product| {
+            !matches!(product.deleted_at_utc, Some(deleted_at_utc) if deleted_at_utc < deleted_before_utc)
+        });
+
+        Ok((count_before - lock.len()) as u64)
+    }
+}
+
+fn product_filter_document(filter: &ProductFilter) -> Document {
+    let mut document = doc! {"deleted_at_utc": null};
+
+    let mut price = Document::new();
+    if let Some(min_price) = filter.min_price {
+        price.insert("$gte", min_price);
+    }
+    if let Some(max_price) = filter.max_price {
+        price.insert("$lte", max_price);
+    }
+    if !price.is_empty() {
+        document.insert("price", price);
+    }
+
+    if let Some(min_stars) = filter.min_stars {
+        document.insert("stars", doc! {"$gte": min_stars as i32});
+    }
+
+    // Sellable inventory is derived from two fields, so this part is evaluated per document
+    // after the indexed predicates have narrowed the candidates
+    if filter.in_stock_only {
+        document.insert(
+            "$expr",
+            doc! {"$gt": ["$available_inventory", "$reserved_inventory"]},
+        );
+    }
+
+    document
+}
+
+fn product_sort_field_name(sort_by: ProductSortField) -> &'static str {
+    match sort_by {
+        ProductSortField::CreatedAt => "created_at_utc",
+        ProductSortField::Price => "price",
+        ProductSortField::Rating => "stars",
+        ProductSortField::Name => "name",
     }
 }
 
+fn product_sort_document(request: &ProductPageRequest) -> Document {
+    let direction = match request.sort_order {
+        SortOrder::Asc => 1,
+        SortOrder::Desc => -1,
+    };
+
+    doc! {product_sort_field_name(request.sort_by): direction, "id": 1}
+}
+
+fn buckets_by_count(counts: HashMap<String, u64>) -> Vec<FacetBucket> {
+    let mut buckets: Vec<FacetBucket> = counts
+        .into_iter()
+        .map(|(value, count)| FacetBucket { value, count })
+        .collect();
+    buckets.sort_by(|left, right| {
+        right
+            .count
+            .cmp(&left.count)
+            .then_with(|| left.value.cmp(&right.value))
+    });
+
+    buckets
+}
+
+fn product_search_document(filter: &ProductFilter, search_text: &str) -> Document {
+    let mut document = product_filter_document(filter);
+    document.insert("$text", doc! {"$search": search_text});
+
+    document
+}
+
+// Group stages for the `$facet` aggregation, mirroring the bucket order of the in-memory
+// repository
+fn facet_pipelines() -> Document {
+    let count = doc! {"$sum": 1};
+    let by_count = doc! {"$sort": {"count": -1, "_id": 1}};
+
+    doc! {
+        "price": [{"$bucket": {
+            "groupBy": "$price",
+            "boundaries": PRICE_FACET_BOUNDARIES.to_vec(),
+            "default": PRICE_FACET_BOUNDARIES[PRICE_FACET_BOUNDARIES.len() - 1],
+            "output": {"count": count.clone()},
+        }}],
+        "rating": [
+            {"$group": {"_id": "$stars", "count": count.clone()}},
+            {"$sort": {"_id": 1}},
+        ],
+        "stock": [
+            {"$group": {
+                "_id": {"$gt": ["$available_inventory", "$reserved_inventory"]},
+                "count": count.clone(),
+            }},
+            {"$sort": {"_id": -1}},
+        ],
+        "category": [
+            {"$match": {"category": {"$ne": null}}},
+            {"$group": {"_id": "$category", "count": count.clone()}},
+            by_count.clone(),
+        ],
+        "brand": [
+            {"$match": {"brand": {"$ne": null}}},
+            {"$group": {"_id": "$brand", "count": count}},
+            by_count,
+        ],
+    }
+}
+
+#[derive(Deserialize)]
+struct FacetCount {
+    #[serde(rename = "_id")]
+    value: Bson,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+struct FacetCounts {
+    price: Vec<FacetCount>,
+    rating: Vec<FacetCount>,
+    stock: Vec<FacetCount>,
+    category: Vec<FacetCount>,
+    brand: Vec<FacetCount>,
+}
+
+fn facet_buckets(
+    counts: Vec<FacetCount>,
+    value: impl Fn(&Bson) -> Option<String>,
+) -> Vec<FacetBucket> {
+    counts
+        .into_iter()
+        .filter_map(|facet_count| {
+            value(&facet_count.value).map(|value| FacetBucket {
+                value,
+                count: facet_count.count,
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ScoredProduct {
+    #[serde(flatten)]
+    product: Product,
+    score: f64,
+}
+
 #[derive(Clone)]
 pub struct MongoDbProductRepository {
     product_collection: Collection<Product>,
@@ -108,9 +548,61 @@ impl MongoDbProductRepository {
     pub async fn new(info: &MongoDbInitializationInfo, client: &Client) -> Self {
         let database = client.database(&info.database);
 
-        MongoDbProductRepository {
-            product_collection: database.collection(&info.collection),
+        let product_collection: Collection<Product> = database.collection(&info.collection);
+
+        // One index per listing sort, each leading with the soft-delete marker every listing
+        // filters on and ending with the id tie-breaker
+        let listing_indexes = [
+            ProductSortField::CreatedAt,
+            ProductSortField::Price,
+            ProductSortField::Rating,
+            ProductSortField::Name,
+        ]
+        .into_iter()
+        .map(|sort_by| {
+            IndexModel::builder()
+                .keys(doc! {"deleted_at_utc": 1, product_sort_field_name(sort_by): 1, "id": 1})
+                .build()
+        });
+        if let Err(e) = product_collection.create_indexes(listing_indexes).await {
+            event!(
+                Level::WARN,
+                "Failed to create product listing indexes: {}",
+                e
+            );
         }
+
+        // Single and batch lookups by id, and single lookups by slug or SKU
+        let lookup_indexes = ["id", "slug", "sku"]
+            .into_iter()
+            .map(|field| IndexModel::builder().keys(doc! {field: 1}).build());
+        if let Err(e) = product_collection.create_indexes(lookup_indexes).await {
+            event!(
+                Level::WARN,
+                "Failed to create product lookup indexes: {}",
+                e
+            );
+        }
+
+        // A collection can only have one text index, so it covers both searchable fields
+        let text_index = IndexModel::builder()
+            .keys(doc! {"name": "text", "description": "text"})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("product_text_search"))
+                    .weights(doc! {"name": NAME_WEIGHT, "description": DESCRIPTION_WEIGHT})
+                    .build(),
+            )
+            .build();
+        if let Err(e) = product_collection.create_index(text_index).await {
+            event!(
+                Level::WARN,
+                "Failed to create product text search index: {}",
+                e
+            );
+        }
+
+        MongoDbProductRepository { product_collection }
     }
 }
 
@@ -121,7 +613,7 @@ impl ProductRepository for MongoDbProductRepository {
         id: String,
         product: Product,
         session: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String> {
+    ) -> Result<Product, DomainError> {
         let mut guard = session.lock().await;
 
         match self
@@ -138,53 +630,240 @@ impl ProductRepository for MongoDbProductRepository {
             {
                 Ok(find_one_product_option) => match find_one_product_option {
                     Some(p) => Ok(p),
-                    None => Err(format!("Failed to find product with id {}", id)),
+                    None => Err(DomainError::NotFound(format!(
+                        "Failed to find product with id {}",
+                        id
+                    ))),
                 },
-                Err(e) => Err(format!("Failed to insert product: {}", e)),
+                Err(e) => Err(transaction_error("Failed to insert product", e)),
             },
-            Err(e) => Err(format!("Failed to insert product: {}", e)),
+            Err(e) => Err(transaction_error("Failed to insert product", e)),
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Product, String> {
-        match self.product_collection.find_one(doc! {"id": &id}).await {
+    async fn read<'a>(&self, id: &'a str) -> Result<Product, DomainError> {
+        match self
+            .product_collection
+            .find_one(doc! {"id": &id, "deleted_at_utc": null})
+            .await
+        {
             Ok(find_one_product_option) => match find_one_product_option {
                 Some(p) => Ok(p),
-                None => Err(format!("Failed to find product with id {}", id)),
+                None => Err(DomainError::NotFound(format!(
+                    "Failed to find product with id {}",
+                    id
+                ))),
             },
-            Err(e) => Err(format!("Failed to insert product: {}", e)),
+            Err(e) => Err(DomainError::Infrastructure(format!(
+                "Failed to insert product: {}",
+                e
+            ))),
         }
     }
 
-    async fn read_all(&self) -> Result<Vec<Product>, String> {
-        let mut products_to_return = Vec::new();
+    async fn read_deleted<'a>(&self, id: &'a str) -> Result<Product, DomainError> {
+        match self
+            .product_collection
+            .find_one(doc! {"id": &id, "deleted_at_utc": {"$ne": null}})
+            .await
+        {
+            Ok(find_one_product_option) => match find_one_product_option {
+                Some(p) => Ok(p),
+                None => Err(DomainError::NotFound(format!(
+                    "Failed to find deleted product with id {}",
+                    id
+                ))),
+            },
+            Err(e) => Err(DomainError::Infrastructure(format!(
+                "Failed to find deleted product: {}",
+                e
+            ))),
+        }
+    }
 
-        match self.product_collection.find(doc! {}).await {
-            Ok(mut found_products) => {
-                while let Ok(Some(product)) = found_products.try_next().await {
-                    products_to_return.push(product.clone())
-                }
+    async fn read_by_key(&self, key: &ProductKey) -> Result<Product, DomainError> {
+        let filter = match key {
+            ProductKey::Id(id) => doc! {"id": id, "deleted_at_utc": null},
+            ProductKey::Slug(slug) => doc! {"slug": slug, "deleted_at_utc": null},
+            ProductKey::Sku(sku) => doc! {"sku": sku, "deleted_at_utc": null},
+        };
 
-                Ok(products_to_return)
-            }
-            Err(_) => Err(format!("Failed to find products")),
+        match self
+            .product_collection
+            .find_one(filter)
+            .sort(doc! {"created_at_utc": 1, "id": 1})
+            .await
+        {
+            Ok(Some(product)) => Ok(product),
+            Ok(None) => Err(DomainError::NotFound(format!(
+                "Failed to find product with {}",
+                key
+            ))),
+            Err(e) => Err(DomainError::Infrastructure(format!(
+                "Failed to find product with {}: {}",
+                key, e
+            ))),
         }
     }
 
+    async fn read_many(&self, ids: &[String]) -> Result<Vec<Product>, DomainError> {
+        self.product_collection
+            .find(doc! {"id": {"$in": ids}, "deleted_at_utc": null})
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to find products: {}", e)))?
+            .try_collect()
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to read products: {}", e)))
+    }
+
+    async fn read_page(&self, request: &ProductPageRequest) -> Result<ProductPage, DomainError> {
+        let filter = product_filter_document(&request.filter);
+
+        let total_count = self
+            .product_collection
+            .count_documents(filter.clone())
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to count products: {}", e)))?;
+
+        let products = self
+            .product_collection
+            .find(filter)
+            .sort(product_sort_document(request))
+            .skip(request.offset)
+            .limit(request.limit as i64)
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to find products: {}", e)))?
+            .try_collect()
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to read products: {}", e)))?;
+
+        Ok(ProductPage {
+            products,
+            total_count,
+        })
+    }
+
+    async fn search(
+        &self,
+        request: &ProductSearchRequest,
+    ) -> Result<ProductSearchPage, DomainError> {
+        let filter = product_search_document(&request.filter, &request.text);
+
+        let total_count = self
+            .product_collection
+            .count_documents(filter.clone())
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to count products: {}", e)))?;
+
+        let scored_products: Vec<ScoredProduct> = self
+            .product_collection
+            .clone_with_type::<ScoredProduct>()
+            .find(filter)
+            .projection(doc! {"score": {"$meta": "textScore"}})
+            .sort(doc! {"score": {"$meta": "textScore"}, "id": 1})
+            .skip(request.offset)
+            .limit(request.limit as i64)
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to search products: {}", e)))?
+            .try_collect()
+            .await
+            .map_err(|e| DomainError::Infrastructure(format!("Failed to read products: {}", e)))?;
+
+        Ok(ProductSearchPage {
+            hits: scored_products
+                .into_iter()
+                .map(|scored_product| ProductSearchHit {
+                    product: scored_product.product,
+                    score: scored_product.score,
+                })
+                .collect(),
+            total_count,
+        })
+    }
+
+    async fn facets(
+        &self,
+        filter: &ProductFilter,
+        search_text: Option<&str>,
+    ) -> Result<ProductFacets, DomainError> {
+        let filter = match search_text {
+            Some(search_text) => product_search_document(filter, search_text),
+            None => product_filter_document(filter),
+        };
+        let pipeline = vec![doc! {"$match": filter}, doc! {"$facet": facet_pipelines()}];
+
+        let facet_counts = self
+            .product_collection
+            .aggregate(pipeline)
+            .with_type::<FacetCounts>()
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!("Failed to aggregate product facets: {}", e))
+            })?
+            .try_next()
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!("Failed to read product facets: {}", e))
+            })?;
+
+        // `$facet` always emits a single document, even when nothing matched
+        Ok(match facet_counts {
+            Some(facet_counts) => ProductFacets {
+                price: facet_buckets(facet_counts.price, |lower_bound| {
+                    lower_bound
+                        .as_f64()
+                        .map(|lower_bound| price_facet_value(price_facet_bucket(lower_bound)))
+                }),
+                rating: facet_buckets(facet_counts.rating, |stars| {
+                    stars.as_i32().map(|stars| stars.to_string())
+                }),
+                stock: facet_buckets(facet_counts.stock, |in_stock| {
+                    in_stock
+                        .as_bool()
+                        .map(|in_stock| stock_facet_value(in_stock).to_string())
+                }),
+                category: facet_buckets(facet_counts.category, |category| {
+                    category.as_str().map(String::from)
+                }),
+                brand: facet_buckets(facet_counts.brand, |brand| brand.as_str().map(String::from)),
+            },
+            None => ProductFacets::default(),
+        })
+    }
+
     async fn update(
         &self,
         id: String,
-        product: Product,
+        mut product: Product,
         session: Arc<Mutex<ClientSession>>,
-    ) -> Result<Product, String> {
+    ) -> Result<Product, DomainError> {
         let mut guard = session.lock().await;
 
+        let expected_version = product.version;
+        product.version += 1;
+
         match self
             .product_collection
-            .replace_one(doc! {"id": &id}, product)
+            .replace_one(doc! {"id": &id, "version": expected_version}, product)
             .session(&mut *guard)
             .await
         {
+            Ok(update_result) if update_result.matched_count == 0 => match self
+                .product_collection
+                .find_one(doc! {"id": &id})
+                .session(&mut *guard)
+                .await
+            {
+                Ok(Some(p)) => Err(DomainError::Conflict(format!(
+                    "Product with id {} is at version {} but version {} was expected",
+                    id, p.version, expected_version
+                ))),
+                Ok(None) => Err(DomainError::NotFound(format!(
+                    "Failed to find Product with id {}",
+                    id
+                ))),
+                Err(e) => Err(transaction_error("Failed to update Product", e)),
+            },
             Ok(_) => match self
                 .product_collection
                 .find_one(doc! {"id": &id})
@@ -193,15 +872,862 @@ impl ProductRepository for MongoDbProductRepository {
             {
                 Ok(find_one_product_option) => match find_one_product_option {
                     Some(p) => Ok(p),
-                    None => Err(format!("Failed to find Product with id {}", id)),
+                    None => Err(DomainError::NotFound(format!(
+                        "Failed to find Product with id {}",
+                        id
+                    ))),
                 },
-                Err(e) => Err(format!("Failed to update Product: {}", e)),
+                Err(e) => Err(transaction_error("Failed to update Product", e)),
             },
-            Err(e) => Err(format!("Failed to update Product: {}", e)),
+            Err(e) => Err(transaction_error("Failed to update Product", e)),
         }
     }
 
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>) {
-        todo!()
+    async fn adjust_inventory(
+        &self,
+        id: &str,
+        available_delta: i64,
+        reserved_delta: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Product, DomainError> {
+        let mut guard = session.lock().await;
+
+        // Guard filters keep the $inc from ever taking a counter below zero
+        let mut filter = doc! {"id": id, "deleted_at_utc": null};
+        if available_delta < 0 {
+            filter.insert("available_inventory", doc! {"$gte": -available_delta});
+        }
+        if reserved_delta < 0 {
+            filter.insert("reserved_inventory", doc! {"$gte": -reserved_delta});
+        }
+        // Taking stock away or reserving more must leave the reservations covered
+        if reserved_delta > 0 || available_delta < 0 {
+            filter.insert(
+                "$expr",
+                doc! {"$gte": [
+                    {"$subtract": [
+                        {"$add": ["$available_inventory", available_delta]},
+                        "$reserved_inventory",
+                    ]},
+                    reserved_delta,
+                ]},
+            );
+        }
+
+        let update = doc! {
+            "$inc": {
+                "available_inventory": available_delta,
+                "reserved_inventory": reserved_delta,
+                "version": 1,
+            },
+            "$set": {"updated_at_utc": chrono::Utc::now().timestamp_millis()},
+        };
+
+        match self
+            .product_collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .session(&mut *guard)
+            .await
+        {
+            Ok(Some(p)) => Ok(p),
+            Ok(None) => match self
+                .product_collection
+                .find_one(doc! {"id": id, "deleted_at_utc": null})
+                .session(&mut *guard)
+                .await
+            {
+                Ok(Some(_)) => Err(DomainError::InsufficientStock(format!(
+                    "Inventory for Product with id {} cannot go below zero",
+                    id
+                ))),
+                Ok(None) => Err(DomainError::NotFound(format!(
+                    "Failed to find Product with id {}",
+                    id
+                ))),
+                Err(e) => Err(transaction_error("Failed to adjust Product inventory", e)),
+            },
+            Err(e) => Err(transaction_error("Failed to adjust Product inventory", e)),
+        }
+    }
+
+    async fn delete_all_deleted_before(
+        &self,
+        deleted_before_utc: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<u64, DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .product_collection
+            .delete_many(doc! {"deleted_at_utc": {"$ne": null, "$lt": deleted_before_utc}})
+            .session(&mut *guard)
+            .await
+        {
+            Ok(delete_result) => Ok(delete_result.deleted_count),
+            Err(e) => Err(transaction_error("Failed to delete deleted Products", e)),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ReservationRepository {
+    async fn read<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError>;
+    async fn read_all_expired_before(
+        &self,
+        expired_before_utc: i64,
+    ) -> Result<Vec<Reservation>, DomainError>;
+    async fn upsert(
+        &self,
+        reservation: Reservation,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Reservation, DomainError>;
+    /// Removes the reservation only if it is still expired, so a cart that refreshed it
+    /// in the meantime keeps its stock.
+    async fn delete_if_expired<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        expired_before_utc: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError>;
+    async fn delete<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<(), DomainError>;
+}
+
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct InMemoryReservationRepository {
+    reservations: Arc<Mutex<HashMap<(String, String), Reservation>>>,
+}
+
+#[allow(dead_code)]
+impl InMemoryReservationRepository {
+    pub fn new() -> Self {
+        InMemoryReservationRepository {
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl ReservationRepository for InMemoryReservationRepository {
+    async fn read<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError> {
+        let lock = self.reservations.lock().await;
+        Ok(lock
+            .get(&(cart_id.to_string(), product_id.to_string()))
+            .cloned())
+    }
+
+    async fn read_all_expired_before(
+        &self,
+        expired_before_utc: i64,
+    ) -> Result<Vec<Reservation>, DomainError> {
+        let lock = self.reservations.lock().await;
+        Ok(lock
+            .values()
+            .filter(|reservation| reservation.expires_at_utc < expired_before_utc)
+            .cloned()
+            .collect())
+    }
+
+    async fn upsert(
+        &self,
+        reservation: Reservation,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<Reservation, DomainError> {
+        let mut lock = self.reservations.lock().await;
+        lock.insert(
+            (reservation.cart_id.clone(), reservation.product_id.clone()),
+            reservation.clone(),
+        );
+        Ok(reservation)
+    }
+
+    async fn delete<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<(), DomainError> {
+        let mut lock = self.reservations.lock().await;
+        lock.remove(&(cart_id.to_string(), product_id.to_string()));
+        Ok(())
+    }
+
+    async fn delete_if_expired<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        expired_before_utc: i64,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError> {
+        let mut lock = self.reservations.lock().await;
+        let key = (cart_id.to_string(), product_id.to_string());
+        match lock.get(&key) {
+            Some(reservation) if reservation.expires_at_utc < expired_before_utc => {
+                Ok(lock.remove(&key))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoDbReservationRepository {
+    reservation_collection: Collection<Reservation>,
+}
+
+impl MongoDbReservationRepository {
+    pub async fn new(info: &MongoDbInitializationInfo, client: &Client) -> Self {
+        let database = client.database(&info.database);
+
+        let reservation_collection: Collection<Reservation> =
+            database.collection(&info.reservation_collection);
+
+        // A cart holds at most one reservation per product, so concurrent first reservations
+        // upsert the same document instead of inserting two
+        let cart_product_index = IndexModel::builder()
+            .keys(doc! {"cart_id": 1, "product_id": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        if let Err(e) = reservation_collection
+            .create_index(cart_product_index)
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Failed to create reservation cart index: {}",
+                e
+            );
+        }
+
+        // The reaper scans for reservations past their expiry on every sweep
+        let expiry_index = IndexModel::builder()
+            .keys(doc! {"expires_at_utc": 1})
+            .build();
+        if let Err(e) = reservation_collection.create_index(expiry_index).await {
+            event!(
+                Level::WARN,
+                "Failed to create reservation expiry index: {}",
+                e
+            );
+        }
+
+        MongoDbReservationRepository {
+            reservation_collection,
+        }
+    }
+}
+
+#[async_trait]
+impl ReservationRepository for MongoDbReservationRepository {
+    async fn read<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .reservation_collection
+            .find_one(doc! {"cart_id": cart_id, "product_id": product_id})
+            .session(&mut *guard)
+            .await
+        {
+            Ok(find_one_reservation_option) => Ok(find_one_reservation_option),
+            Err(e) => Err(transaction_error("Failed to find reservation", e)),
+        }
+    }
+
+    async fn read_all_expired_before(
+        &self,
+        expired_before_utc: i64,
+    ) -> Result<Vec<Reservation>, DomainError> {
+        self.reservation_collection
+            .find(doc! {"expires_at_utc": {"$lt": expired_before_utc}})
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!("Failed to find expired reservations: {}", e))
+            })?
+            .try_collect()
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!("Failed to read expired reservations: {}", e))
+            })
+    }
+
+    async fn upsert(
+        &self,
+        reservation: Reservation,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Reservation, DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .reservation_collection
+            .replace_one(
+                doc! {"cart_id": &reservation.cart_id, "product_id": &reservation.product_id},
+                &reservation,
+            )
+            .upsert(true)
+            .session(&mut *guard)
+            .await
+        {
+            Ok(_) => Ok(reservation),
+            Err(e) => Err(transaction_error("Failed to save reservation", e)),
+        }
+    }
+
+    async fn delete<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<(), DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .reservation_collection
+            .delete_one(doc! {"cart_id": cart_id, "product_id": product_id})
+            .session(&mut *guard)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(transaction_error("Failed to delete reservation", e)),
+        }
+    }
+
+    async fn delete_if_expired<'a>(
+        &self,
+        cart_id: &'a str,
+        product_id: &'a str,
+        expired_before_utc: i64,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<Option<Reservation>, DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .reservation_collection
+            .find_one_and_delete(doc! {
+                "cart_id": cart_id,
+                "product_id": product_id,
+                "expires_at_utc": {"$lt": expired_before_utc},
+            })
+            .session(&mut *guard)
+            .await
+        {
+            Ok(deleted_reservation_option) => Ok(deleted_reservation_option),
+            Err(e) => Err(transaction_error("Failed to delete expired reservation", e)),
+        }
+    }
+}
+
+#[async_trait]
+pub trait OutboxRepository {
+    async fn create(
+        &self,
+        message: OutboxMessage,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<OutboxMessage, DomainError>;
+    /// Returns undelivered messages that are due and have not used up their attempts,
+    /// oldest first.
+    async fn read_pending(
+        &self,
+        due_before_utc: i64,
+        max_attempts: u32,
+        limit: i64,
+    ) -> Result<Vec<OutboxMessage>, DomainError>;
+    async fn mark_delivered<'a>(
+        &self,
+        id: &'a str,
+        delivered_at_utc: i64,
+    ) -> Result<(), DomainError>;
+    async fn record_failure<'a>(
+        &self,
+        id: &'a str,
+        next_attempt_at_utc: i64,
+        error: &'a str,
+    ) -> Result<(), DomainError>;
+}
+
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct InMemoryOutboxRepository {
+    messages: Arc<Mutex<HashMap<String, OutboxMessage>>>,
+}
+
+#[allow(dead_code)]
+impl InMemoryOutboxRepository {
+    pub fn new() -> Self {
+        InMemoryOutboxRepository {
+            messages: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for InMemoryOutboxRepository {
+    async fn create(
+        &self,
+        message: OutboxMessage,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<OutboxMessage, DomainError> {
+        let mut lock = self.messages.lock().await;
+        lock.insert(message.id.clone(), message.clone());
+        Ok(message)
+    }
+
+    async fn read_pending(
+        &self,
+        due_before_utc: i64,
+        max_attempts: u32,
+        limit: i64,
+    ) -> Result<Vec<OutboxMessage>, DomainError> {
+        let lock = self.messages.lock().await;
+        let mut pending_messages: Vec<OutboxMessage> = lock
+            .values()
+            .filter(|message| {
+                message.delivered_at_utc.is_none()
+                    && message.next_attempt_at_utc <= due_before_utc
+                    && message.attempts < max_attempts
+            })
+            .cloned()
+            .collect();
+
+        pending_messages.sort_by_key(|message| message.created_at_utc);
+        pending_messages.truncate(limit as usize);
+
+        Ok(pending_messages)
+    }
+
+    async fn mark_delivered<'a>(
+        &self,
+        id: &'a str,
+        delivered_at_utc: i64,
+    ) -> Result<(), DomainError> {
+        let mut lock = self.messages.lock().await;
+        match lock.get_mut(id) {
+            Some(message) => {
+                message.delivered_at_utc = Some(delivered_at_utc);
+                Ok(())
+            }
+            None => Err(DomainError::NotFound(format!(
+                "Outbox message with id {} did not exist",
+                id
+            ))),
+        }
+    }
+
+    async fn record_failure<'a>(
+        &self,
+        id: &'a str,
+        next_attempt_at_utc: i64,
+        error: &'a str,
+    ) -> Result<(), DomainError> {
+        let mut lock = self.messages.lock().await;
+        match lock.get_mut(id) {
+            Some(message) => {
+                message.attempts += 1;
+                message.next_attempt_at_utc = next_attempt_at_utc;
+                message.last_error = Some(error.to_string());
+                Ok(())
+            }
+            None => Err(DomainError::NotFound(format!(
+                "Outbox message with id {} did not exist",
+                id
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoDbOutboxRepository {
+    outbox_collection: Collection<OutboxMessage>,
+}
+
+impl MongoDbOutboxRepository {
+    pub async fn new(info: &MongoDbInitializationInfo, client: &Client) -> Self {
+        let database = client.database(&info.database);
+
+        let outbox_collection: Collection<OutboxMessage> =
+            database.collection(&info.outbox_collection);
+
+        // The relay polls for undelivered messages that are due, oldest first
+        let pending_index = IndexModel::builder()
+            .keys(doc! {"delivered_at_utc": 1, "next_attempt_at_utc": 1, "created_at_utc": 1})
+            .build();
+        if let Err(e) = outbox_collection.create_index(pending_index).await {
+            event!(Level::WARN, "Failed to create outbox pending index: {}", e);
+        }
+
+        MongoDbOutboxRepository { outbox_collection }
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for MongoDbOutboxRepository {
+    async fn create(
+        &self,
+        message: OutboxMessage,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<OutboxMessage, DomainError> {
+        let mut guard = session.lock().await;
+
+        match self
+            .outbox_collection
+            .insert_one(&message)
+            .session(&mut *guard)
+            .await
+        {
+            Ok(_) => Ok(message),
+            Err(e) => Err(transaction_error("Failed to insert outbox message", e)),
+        }
+    }
+
+    async fn read_pending(
+        &self,
+        due_before_utc: i64,
+        max_attempts: u32,
+        limit: i64,
+    ) -> Result<Vec<OutboxMessage>, DomainError> {
+        self.outbox_collection
+            .find(doc! {
+                "delivered_at_utc": null,
+                "next_attempt_at_utc": {"$lte": due_before_utc},
+                "attempts": {"$lt": max_attempts},
+            })
+            .sort(doc! {"created_at_utc": 1})
+            .limit(limit)
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!(
+                    "Failed to find pending outbox messages: {}",
+                    e
+                ))
+            })?
+            .try_collect()
+            .await
+            .map_err(|e| {
+                DomainError::Infrastructure(format!(
+                    "Failed to read pending outbox messages: {}",
+                    e
+                ))
+            })
+    }
+
+    async fn mark_delivered<'a>(
+        &self,
+        id: &'a str,
+        delivered_at_utc: i64,
+    ) -> Result<(), DomainError> {
+        match self
+            .outbox_collection
+            .update_one(
+                doc! {"id": id},
+                doc! {"$set": {"delivered_at_utc": delivered_at_utc}},
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(DomainError::Infrastructure(format!(
+                "Failed to mark outbox message delivered: {}",
+                e
+            ))),
+        }
+    }
+
+    async fn record_failure<'a>(
+        &self,
+        id: &'a str,
+        next_attempt_at_utc: i64,
+        error: &'a str,
+    ) -> Result<(), DomainError> {
+        match self
+            .outbox_collection
+            .update_one(
+                doc! {"id": id},
+                doc! {
+                    "$inc": {"attempts": 1},
+                    "$set": {"next_attempt_at_utc": next_attempt_at_utc, "last_error": error},
+                },
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(DomainError::Infrastructure(format!(
+                "Failed to record outbox delivery failure: {}",
+                e
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ProcessedMessageRepository {
+    /// Records the message as processed, returning `false` if it had already been recorded.
+    async fn insert_if_absent(
+        &self,
+        message: ProcessedMessage,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<bool, DomainError>;
+}
+
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct InMemoryProcessedMessageRepository {
+    messages: Arc<Mutex<HashMap<String, ProcessedMessage>>>,
+}
+
+#[allow(dead_code)]
+impl InMemoryProcessedMessageRepository {
+    pub fn new() -> Self {
+        InMemoryProcessedMessageRepository {
+            messages: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl ProcessedMessageRepository for InMemoryProcessedMessageRepository {
+    async fn insert_if_absent(
+        &self,
+        message: ProcessedMessage,
+        _: Arc<Mutex<ClientSession>>,
+    ) -> Result<bool, DomainError> {
+        let mut lock = self.messages.lock().await;
+        match lock.contains_key(&message.id) {
+            true => Ok(false),
+            false => {
+                lock.insert(message.id.clone(), message);
+                Ok(true)
+            }
+        }
+    }
+}
+
+// Redeliveries arrive within minutes, so a week of processed ids is plenty to deduplicate them
+const PROCESSED_MESSAGE_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct MongoDbProcessedMessageRepository {
+    processed_message_collection: Collection<ProcessedMessage>,
+}
+
+impl MongoDbProcessedMessageRepository {
+    pub async fn new(info: &MongoDbInitializationInfo, client: &Client) -> Self {
+        let database = client.database(&info.database);
+
+        let processed_message_collection: Collection<ProcessedMessage> =
+            database.collection(&info.processed_message_collection);
+
+        // The unique index is what stops two concurrent deliveries of a message from both
+        // being recorded, and the TTL index forgets messages long after any redelivery
+        let processed_message_indexes = [
+            IndexModel::builder()
+                .keys(doc! {"id": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"processed_at": 1})
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(PROCESSED_MESSAGE_RETENTION)
+                        .build(),
+                )
+                .build(),
+        ];
+        if let Err(e) = processed_message_collection
+            .create_indexes(processed_message_indexes)
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Failed to create processed message indexes: {}",
+                e
+            );
+        }
+
+        MongoDbProcessedMessageRepository {
+            processed_message_collection,
+        }
+    }
+}
+
+#[async_trait]
+impl ProcessedMessageRepository for MongoDbProcessedMessageRepository {
+    async fn insert_if_absent(
+        &self,
+        message: ProcessedMessage,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<bool, DomainError> {
+        let mut guard = session.lock().await;
+
+        // A concurrent delivery of the same message hits the unique index here and fails with a
+        // write conflict; its retried transaction then finds the message already recorded.
+        // TTL indexes only expire dates, so the timestamp is also stored as one.
+        match self
+            .processed_message_collection
+            .update_one(
+                doc! {"id": &message.id},
+                doc! {"$setOnInsert": {
+                    "id": &message.id,
+                    "processed_at_utc": message.processed_at_utc,
+                    "processed_at": DateTime::from_millis(message.processed_at_utc),
+                }},
+            )
+            .upsert(true)
+            .session(&mut *guard)
+            .await
+        {
+            Ok(result) => Ok(result.upserted_id.is_some()),
+            Err(e) => Err(transaction_error("Failed to record processed message", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{client_session, product};
+
+    fn rated(id: &str, price: f32, available_inventory: u32, stars: u8) -> Product {
+        Product {
+            available_inventory,
+            stars,
+            ..product(id, &format!("product {}", id), price)
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_read_page_filters_sorts_and_pages() {
+        // Arrange
+        let mut deleted_product = rated("5", 15.0, 10, 5);
+        deleted_product.deleted_at_utc = Some(1);
+        let product_repository = InMemoryProductRepository::with_products(vec![
+            rated("1", 30.0, 10, 4),
+            rated("2", 10.0, 10, 5),
+            rated("3", 20.0, 0, 5),
+            rated("4", 25.0, 10, 2),
+            deleted_product,
+        ]);
+        let request = ProductPageRequest {
+            filter: ProductFilter {
+                min_price: Some(5.0),
+                max_price: Some(30.0),
+                in_stock_only: true,
+                min_stars: Some(3),
+            },
+            sort_by: ProductSortField::Price,
+            sort_order: SortOrder::Desc,
+            offset: 1,
+            limit: 1,
+        };
+
+        // Act
+        let page = product_repository.read_page(&request).await.unwrap();
+
+        // Assert
+        assert_eq!(page.total_count, 2);
+        assert_eq!(page.products.len(), 1);
+        assert_eq!(page.products[0].id, "2");
+    }
+
+    #[tokio::test]
+    async fn in_memory_facets_count_buckets_over_matching_products() {
+        // Arrange
+        let labelled = |mut product: Product, category: &str, brand: &str| {
+            product.category = Some(category.to_string());
+            product.brand = Some(brand.to_string());
+            product
+        };
+        let mut garden_hose = labelled(rated("3", 600.0, 0, 4), "garden", "acme");
+        garden_hose.description = String::from("Garden hose");
+        let product_repository = InMemoryProductRepository::with_products(vec![
+            labelled(rated("1", 30.0, 10, 4), "kitchen", "acme"),
+            labelled(rated("2", 10.0, 10, 5), "kitchen", "zenith"),
+            garden_hose,
+            rated("4", 20.0, 10, 2),
+        ]);
+        let filter = ProductFilter {
+            min_stars: Some(3),
+            ..ProductFilter::default()
+        };
+        let bucket = |value: &str, count| FacetBucket {
+            value: value.to_string(),
+            count,
+        };
+
+        // Act
+        let facets = product_repository.facets(&filter, None).await.unwrap();
+        let search_facets = product_repository
+            .facets(&filter, Some("hose"))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            facets,
+            ProductFacets {
+                price: vec![bucket("0-25", 1), bucket("25-50", 1), bucket("500+", 1)],
+                rating: vec![bucket("4", 2), bucket("5", 1)],
+                stock: vec![bucket("in_stock", 2), bucket("out_of_stock", 1)],
+                category: vec![bucket("kitchen", 2), bucket("garden", 1)],
+                brand: vec![bucket("acme", 2), bucket("zenith", 1)],
+            }
+        );
+        assert_eq!(search_facets.price, vec![bucket("500+", 1)]);
+        assert_eq!(search_facets.category, vec![bucket("garden", 1)]);
+    }
+
+    #[tokio::test]
+    async fn in_memory_adjust_inventory_rejects_reserving_more_than_is_available() {
+        // Arrange
+        let product_repository = InMemoryProductRepository::with_products(vec![Product {
+            available_inventory: 3,
+            reserved_inventory: 2,
+            ..product("1", "laptop", 10.0)
+        }]);
+        let session = client_session().await;
+
+        // Act
+        let over_reservation = product_repository
+            .adjust_inventory("1", 0, 2, session.clone())
+            .await;
+        let over_release = product_repository
+            .adjust_inventory("1", 0, -3, session.clone())
+            .await;
+        let reservation = product_repository
+            .adjust_inventory("1", 0, 1, session)
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(matches!(
+            over_reservation,
+            Err(DomainError::InsufficientStock(_))
+        ));
+        assert!(matches!(
+            over_release,
+            Err(DomainError::InsufficientStock(_))
+        ));
+        assert_eq!(reservation.available_inventory, 3);
+        assert_eq!(reservation.reserved_inventory, 3);
+        assert_eq!(reservation.version, 1);
     }
 }
@@ -1,41 +1,255 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::{header::IF_MATCH, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
 use std::sync::Arc;
-use axum::{extract::{Json, Path, State}, http::StatusCode};
-use serde_json::{Value, json};
 
-use crate::{cqrs::{CommandHandler, CreateProductCommand, GetProductsQuery, ModifyProductInventoryCommand, QueryHandler}, dtos::ApiError, state::AppState};
+use crate::{
+    cqrs::{
+        BatchGetProductsQuery, CommandHandler, CreateProductCommand, DeleteProductCommand,
+        GetProductQuery, ListProductsQuery, ModifyProductInventoryCommand, QueryHandler,
+        RestoreProductCommand, SearchProductsQuery, UpdateProductCommand,
+    },
+    domain::{DomainError, ProductKey},
+    dtos::{ApiError, HealthResponse},
+    state::AppState,
+};
+
+impl IntoResponse for DomainError {
+    fn into_response(self) -> Response {
+        let status_code = match self {
+            DomainError::Validation(_) => StatusCode::BAD_REQUEST,
+            DomainError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            DomainError::Forbidden(_) => StatusCode::FORBIDDEN,
+            DomainError::NotFound(_) => StatusCode::NOT_FOUND,
+            DomainError::Conflict(_) => StatusCode::CONFLICT,
+            DomainError::InsufficientStock(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            DomainError::Infrastructure(_) | DomainError::Transient(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        (
+            status_code,
+            Json(json!(ApiError {
+                code: self.code().to_string(),
+                error: self.to_string(),
+            })),
+        )
+            .into_response()
+    }
+}
+
+// Accepts both the quoted ETag form ("3") and a bare version number
+fn parse_if_match(headers: &HeaderMap) -> Result<Option<u32>, DomainError> {
+    match headers.get(IF_MATCH) {
+        Some(header_value) => match header_value
+            .to_str()
+            .ok()
+            .map(|value| value.trim().trim_start_matches("W/").trim_matches('"'))
+            .and_then(|value| value.parse::<u32>().ok())
+        {
+            Some(version) => Ok(Some(version)),
+            None => Err(DomainError::Validation(String::from(
+                "If-Match header must contain a product version",
+            ))),
+        },
+        None => Ok(None),
+    }
+}
 
 pub async fn index() -> &'static str {
     "Hello, World!"
 }
 
-pub async fn get_products(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
-    let input = GetProductsQuery {
-        id: id.to_string()
+pub async fn broker_health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    match state.broker_health.is_connected() {
+        true => (
+            StatusCode::OK,
+            Json(json!(HealthResponse {
+                status: String::from("up"),
+                last_error: None,
+            })),
+        ),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!(HealthResponse {
+                status: String::from("down"),
+                last_error: state.broker_health.last_error(),
+            })),
+        ),
+    }
+}
+
+async fn get_product_by_key(
+    state: &AppState,
+    key: ProductKey,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let input = GetProductQuery { key };
+
+    let response = state.get_product_query_handler.handle(Some(input)).await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+pub async fn get_product(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    get_product_by_key(&state, ProductKey::Id(id)).await
+}
+
+pub async fn get_product_by_slug(
+    Path(slug): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    get_product_by_key(&state, ProductKey::Slug(slug)).await
+}
+
+pub async fn get_product_by_sku(
+    Path(sku): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    get_product_by_key(&state, ProductKey::Sku(sku)).await
+}
+
+pub async fn batch_get_products(
+    State(state): State<Arc<AppState>>,
+    Json(batch_get_products_query): Json<BatchGetProductsQuery>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let response = state
+        .batch_get_products_query_handler
+        .handle(Some(batch_get_products_query))
+        .await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+pub async fn list_products(
+    State(state): State<Arc<AppState>>,
+    Query(list_products_query): Query<ListProductsQuery>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let response = state
+        .list_products_query_handler
+        .handle(Some(list_products_query))
+        .await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+pub async fn search_products(
+    State(state): State<Arc<AppState>>,
+    Query(search_products_query): Query<SearchProductsQuery>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let response = state
+        .search_products_query_handler
+        .handle(Some(search_products_query))
+        .await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+pub async fn create_product(
+    state: State<Arc<AppState>>,
+    Json(create_product_command): Json<CreateProductCommand>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let response = state
+        .create_product_command_handler
+        .handle(&create_product_command)
+        .await?;
+    Ok((StatusCode::CREATED, Json(json!(response))))
+}
+
+pub async fn update_product(
+    Path(id): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut update_product_command): Json<UpdateProductCommand>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    update_product_command.product_id = id;
+    update_product_command.expected_version = parse_if_match(&headers)?;
+
+    let response = state
+        .update_product_command_handler
+        .handle(&update_product_command)
+        .await?;
+    Ok((StatusCode::NO_CONTENT, Json(json!(response))))
+}
+
+pub async fn delete_product(
+    Path(id): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let delete_product_command = DeleteProductCommand {
+        product_id: id,
+        expected_version: parse_if_match(&headers)?,
     };
 
-    match state.get_products_query_handler.handle(Some(input)).await {
-        Ok(response)=> (StatusCode::OK, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
-    }
+    let response = state
+        .delete_product_command_handler
+        .handle(&delete_product_command)
+        .await?;
+    Ok((StatusCode::NO_CONTENT, Json(json!(response))))
 }
 
-pub async fn get_all_products(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
-    match state.get_products_query_handler.handle(None).await {
-        Ok(response)=> (StatusCode::OK, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
-    }
+pub async fn restore_product(
+    Path(id): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    let restore_product_command = RestoreProductCommand { product_id: id };
+
+    let response = state
+        .restore_product_command_handler
+        .handle(&restore_product_command)
+        .await?;
+    Ok((StatusCode::NO_CONTENT, Json(json!(response))))
 }
 
-pub async fn create_product(state: State<Arc<AppState>>, Json(create_product_command): Json<CreateProductCommand>) -> (StatusCode, Json<Value>) {
-    match state.create_product_command_handler.handle(&create_product_command).await {
-        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
-    }
+pub async fn modify_product_inventory(
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut modify_product_inventory_command): Json<ModifyProductInventoryCommand>,
+) -> Result<(StatusCode, Json<Value>), DomainError> {
+    modify_product_inventory_command.expected_version = parse_if_match(&headers)?;
+
+    let response = state
+        .modify_product_inventory_command_handler
+        .handle(&modify_product_inventory_command)
+        .await?;
+    Ok((StatusCode::NO_CONTENT, Json(json!(response))))
 }
 
-pub async fn modify_product_inventory(state: State<Arc<AppState>>, Json(modify_product_inventory_command): Json<ModifyProductInventoryCommand>) -> (StatusCode, Json<Value>) {
-    match state.modify_product_inventory_command_handler.handle(&modify_product_inventory_command).await {
-        Ok(response) => (StatusCode::NO_CONTENT, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn domain_error_responses_carry_status_and_error_code() {
+        // Arrange
+        let not_found = DomainError::NotFound(String::from("Product with id 1 did not exist"));
+        let insufficient_stock = DomainError::InsufficientStock(String::from("short"));
+        let transient = DomainError::Transient(String::from("write conflict"));
+
+        // Act
+        let not_found_response = not_found.into_response();
+        let insufficient_stock_response = insufficient_stock.into_response();
+        let transient_response = transient.into_response();
+
+        // Assert
+        assert_eq!(not_found_response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            insufficient_stock_response.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            transient_response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let body = axum::body::to_bytes(not_found_response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let api_error: ApiError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(api_error.code, "not_found");
+        assert_eq!(api_error.error, "Product with id 1 did not exist");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,141 @@
+use crate::domain::Product;
+
+// Name matches outrank description matches; the Mongo text index uses the same weights so both
+// repositories rank results alike
+pub const NAME_WEIGHT: i32 = 10;
+pub const DESCRIPTION_WEIGHT: i32 = 1;
+
+const HIGHLIGHT_START: &str = "<em>";
+const HIGHLIGHT_END: &str = "</em>";
+
+const SNIPPET_LEADING_WORDS: usize = 5;
+const SNIPPET_MAX_WORDS: usize = 20;
+const SNIPPET_ELLIPSIS: &str = "...";
+
+/// Splits text into lowercase alphanumeric terms.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn term_frequency(text: &str, terms: &[String]) -> usize {
+    tokenize(text)
+        .iter()
+        .filter(|term| terms.contains(term))
+        .count()
+}
+
+/// Weighted number of query term occurrences in the product; zero means the product does not
+/// match the search at all.
+pub fn relevance_score(product: &Product, terms: &[String]) -> f64 {
+    (term_frequency(&product.name, terms) as i32 * NAME_WEIGHT
+        + term_frequency(&product.description, terms) as i32 * DESCRIPTION_WEIGHT) as f64
+}
+
+fn highlight_word(word: &str, terms: &[String]) -> String {
+    match tokenize(word).iter().any(|term| terms.contains(term)) {
+        true => format!("{}{}{}", HIGHLIGHT_START, word, HIGHLIGHT_END),
+        false => word.to_string(),
+    }
+}
+
+/// Wraps every word containing a query term in highlight markers.
+pub fn highlight(text: &str, terms: &[String]) -> String {
+    text.split_whitespace()
+        .map(|word| highlight_word(word, terms))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// A highlighted window of the text starting just before the first query term, so long
+/// descriptions show the part that matched.
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let first_match = words
+        .iter()
+        .position(|word| tokenize(word).iter().any(|term| terms.contains(term)))
+        .unwrap_or(0);
+    let start = first_match.saturating_sub(SNIPPET_LEADING_WORDS);
+    let end = words.len().min(start + SNIPPET_MAX_WORDS);
+
+    let mut snippet = words[start..end]
+        .iter()
+        .map(|word| highlight_word(word, terms))
+        .collect::<Vec<String>>()
+        .join(" ");
+    if start > 0 {
+        snippet = format!("{}{}", SNIPPET_ELLIPSIS, snippet);
+    }
+    if end < words.len() {
+        snippet = format!("{}{}", snippet, SNIPPET_ELLIPSIS);
+    }
+
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::product;
+
+    fn described(name: &str, description: &str) -> Product {
+        Product {
+            description: description.to_string(),
+            ..product("1", name, 10.0)
+        }
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_splits_on_punctuation() {
+        // Arrange
+        let text = "Trail-Running Shoes, size 42!";
+
+        // Act
+        let terms = tokenize(text);
+
+        // Assert
+        assert_eq!(terms, vec!["trail", "running", "shoes", "size", "42"]);
+    }
+
+    #[test]
+    fn relevance_score_weights_name_matches_above_description_matches() {
+        // Arrange
+        let terms = tokenize("kettle");
+        let name_match = described("Steel kettle", "Boils water");
+        let description_match = described("Steel pot", "Works like a kettle, or a kettle lid");
+
+        // Act
+        let name_score = relevance_score(&name_match, &terms);
+        let description_score = relevance_score(&description_match, &terms);
+
+        // Assert
+        assert_eq!(name_score, NAME_WEIGHT as f64);
+        assert_eq!(description_score, 2.0 * DESCRIPTION_WEIGHT as f64);
+        assert_eq!(
+            relevance_score(&described("Steel pot", "Boils water"), &terms),
+            0.0
+        );
+    }
+
+    #[test]
+    fn snippet_starts_near_the_first_match_and_highlights_terms() {
+        // Arrange
+        let description = (1..=30)
+            .map(|index| match index {
+                12 => String::from("Kettle,"),
+                _ => format!("word{}", index),
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        // Act
+        let snippet = snippet(&description, &tokenize("kettle"));
+
+        // Assert
+        assert!(snippet.starts_with("...word7 "));
+        assert!(snippet.contains("<em>Kettle,</em>"));
+        assert!(snippet.ends_with("word26..."));
+    }
+}
@@ -1,17 +1,25 @@
 use std::sync::Arc;
 
 use crate::cqrs::{
-    CreateProductCommandHandler, DecrementProductInventoryCommandHandler, GetProductsQueryHandler,
-    IncrementProdcuctInventoryCommandHandler, ModifyProductInventoryCommandHandler,
+    BatchGetProductsQueryHandler, CreateProductCommandHandler, DeleteProductCommandHandler,
+    GetProductQueryHandler, ListProductsQueryHandler, ModifyProductInventoryCommandHandler,
+    RestoreProductCommandHandler, SearchProductsQueryHandler, UpdateProductCommandHandler,
 };
+use crate::events::BrokerHealth;
 
 #[derive(Clone)]
 pub struct AppState {
     pub create_product_command_handler: Arc<CreateProductCommandHandler>,
-    pub get_products_query_handler: Arc<GetProductsQueryHandler>,
+    pub update_product_command_handler: Arc<UpdateProductCommandHandler>,
+    pub delete_product_command_handler: Arc<DeleteProductCommandHandler>,
+    pub restore_product_command_handler: Arc<RestoreProductCommandHandler>,
+    pub get_product_query_handler: Arc<GetProductQueryHandler>,
+    pub batch_get_products_query_handler: Arc<BatchGetProductsQueryHandler>,
+    pub list_products_query_handler: Arc<ListProductsQueryHandler>,
+    pub search_products_query_handler: Arc<SearchProductsQueryHandler>,
     pub modify_product_inventory_command_handler: Arc<ModifyProductInventoryCommandHandler>,
-    pub decrement_product_inventory_command_handler: Arc<DecrementProductInventoryCommandHandler>,
-    pub increment_product_inventory_command_handler: Arc<IncrementProdcuctInventoryCommandHandler>,
     pub auth0_domain: String,
     pub auth0_audience: String,
+    pub auth0_admin_scope: String,
+    pub broker_health: Arc<BrokerHealth>,
 }
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use mongodb::{Client, ClientSession};
+use tokio::sync::Mutex;
+
+use crate::{
+    domain::Product,
+    events::Event,
+    repositories::{
+        InMemoryProcessedMessageRepository, InMemoryProductRepository,
+        InMemoryReservationRepository,
+    },
+    uow::MockUnitOfWork,
+};
+
+/// A live product without inventory; tests override the fields they care about with struct
+/// update syntax.
+pub fn product(id: &str, name: &str, price: f32) -> Product {
+    Product {
+        id: id.to_string(),
+        name: name.to_string(),
+        price,
+        description: String::from("desc"),
+        category: None,
+        brand: None,
+        slug: String::new(),
+        sku: None,
+        available_inventory: 0,
+        reserved_inventory: 0,
+        stars: 0,
+        number_of_reviews: 0,
+        created_at_utc: 0,
+        updated_at_utc: 0,
+        version: 0,
+        deleted_at_utc: None,
+    }
+}
+
+// Starting a session does not contact the server, and the in-memory repositories never use it
+pub async fn client_session() -> Arc<Mutex<ClientSession>> {
+    let client = Client::with_uri_str("mongodb://localhost:27017")
+        .await
+        .unwrap();
+
+    Arc::new(Mutex::new(client.start_session().await.unwrap()))
+}
+
+/// In-memory repositories behind a `MockUnitOfWork` whose transactions always commit. Events
+/// registered through the unit of work are kept in `registered_events`.
+#[derive(Clone)]
+pub struct InMemoryRepositories {
+    pub products: Arc<InMemoryProductRepository>,
+    pub reservations: Arc<InMemoryReservationRepository>,
+    pub processed_messages: Arc<InMemoryProcessedMessageRepository>,
+    pub registered_events: Arc<std::sync::Mutex<Vec<Event>>>,
+}
+
+impl InMemoryRepositories {
+    pub fn with_products(products: Vec<Product>) -> Self {
+        InMemoryRepositories {
+            products: Arc::new(InMemoryProductRepository::with_products(products)),
+            reservations: Arc::new(InMemoryReservationRepository::new()),
+            processed_messages: Arc::new(InMemoryProcessedMessageRepository::new()),
+            registered_events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn registered_events(&self) -> Vec<Event> {
+        self.registered_events.lock().unwrap().clone()
+    }
+
+    pub fn mock_uow(&self) -> MockUnitOfWork {
+        let mut uow = MockUnitOfWork::new();
+
+        let products = self.products.clone();
+        uow.expect_get_product_repository().returning(move || {
+            let products = products.clone();
+            Box::pin(async move { products as _ })
+        });
+        let reservations = self.reservations.clone();
+        uow.expect_get_reservation_repository().returning(move || {
+            let reservations = reservations.clone();
+            Box::pin(async move { reservations as _ })
+        });
+        let processed_messages = self.processed_messages.clone();
+        uow.expect_get_processed_message_repository()
+            .returning(move || {
+                let processed_messages = processed_messages.clone();
+                Box::pin(async move { processed_messages as _ })
+            });
+        uow.expect_begin_transaction()
+            .returning(|| Box::pin(async { Ok(client_session().await) }));
+        let registered_events = self.registered_events.clone();
+        uow.expect_register_event().returning(move |event, _| {
+            registered_events.lock().unwrap().push(event);
+            Box::pin(async { Ok(()) })
+        });
+        uow.expect_commit()
+            .returning(|_| Box::pin(async { Ok(()) }));
+        uow.expect_rollback()
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        uow
+    }
+}
@@ -1,44 +1,74 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use mockall::automock;
-use mongodb::ClientSession;
+use mongodb::{error::UNKNOWN_TRANSACTION_COMMIT_RESULT, Client, ClientSession};
 use tokio::sync::Mutex;
 use tracing::{event, Level};
 
 use crate::{
-    events::{Event, MessageBroker},
-    repositories::ProductRepository,
+    domain::{DomainError, OutboxMessage},
+    events::{current_correlation_id, Event},
+    repositories::{
+        transaction_error, OutboxRepository, ProcessedMessageRepository, ProductRepository,
+        ReservationRepository,
+    },
 };
 
 #[async_trait]
 #[automock]
 pub trait UnitOfWork {
     async fn get_product_repository(&self) -> Arc<dyn ProductRepository + Send + Sync>;
-    async fn begin_transaction(&self) -> Arc<tokio::sync::Mutex<ClientSession>>;
-    async fn commit(&self) -> Result<(), String>;
-    async fn rollback(&self) -> Result<(), String>;
+    async fn get_reservation_repository(&self) -> Arc<dyn ReservationRepository + Send + Sync>;
+    async fn get_processed_message_repository(
+        &self,
+    ) -> Arc<dyn ProcessedMessageRepository + Send + Sync>;
+    /// Starts a transaction on a session of its own, so concurrent commands never share one.
+    /// The returned session is passed to every repository call, event and commit/rollback
+    /// belonging to that command.
+    async fn begin_transaction(
+        &self,
+    ) -> Result<Arc<tokio::sync::Mutex<ClientSession>>, DomainError>;
+    /// Writes the event to the outbox inside the session's transaction; it is published
+    /// by the outbox relay once the transaction has committed.
+    async fn register_event(
+        &self,
+        event: Event,
+        session: Arc<tokio::sync::Mutex<ClientSession>>,
+    ) -> Result<(), DomainError>;
+    async fn commit(
+        &self,
+        session: Arc<tokio::sync::Mutex<ClientSession>>,
+    ) -> Result<(), DomainError>;
+    async fn rollback(
+        &self,
+        session: Arc<tokio::sync::Mutex<ClientSession>>,
+    ) -> Result<(), DomainError>;
 }
 
 #[derive(Clone)]
 pub struct ProductUnitOfWork {
     product_repository: Arc<dyn ProductRepository + Send + Sync>,
-    message_broker: Arc<dyn MessageBroker + Send + Sync>,
-    events_to_publish: Arc<Mutex<Vec<Event>>>,
-    client_session: Arc<Mutex<ClientSession>>,
+    reservation_repository: Arc<dyn ReservationRepository + Send + Sync>,
+    processed_message_repository: Arc<dyn ProcessedMessageRepository + Send + Sync>,
+    outbox_repository: Arc<dyn OutboxRepository + Send + Sync>,
+    client: Client,
 }
 
 impl ProductUnitOfWork {
     pub fn new(
         product_repository: Arc<dyn ProductRepository + Send + Sync>,
-        message_broker: Arc<dyn MessageBroker + Send + Sync>,
-        client_session: Arc<Mutex<ClientSession>>,
+        reservation_repository: Arc<dyn ReservationRepository + Send + Sync>,
+        processed_message_repository: Arc<dyn ProcessedMessageRepository + Send + Sync>,
+        outbox_repository: Arc<dyn OutboxRepository + Send + Sync>,
+        client: Client,
     ) -> ProductUnitOfWork {
         ProductUnitOfWork {
-            product_repository: product_repository,
-            message_broker: message_broker,
-            events_to_publish: Arc::new(Mutex::new(Vec::new())),
-            client_session: client_session,
+            product_repository,
+            reservation_repository,
+            processed_message_repository,
+            outbox_repository,
+            client,
         }
     }
 }
@@ -49,66 +79,216 @@ impl UnitOfWork for ProductUnitOfWork {
         self.product_repository.clone()
     }
 
-    async fn begin_transaction(&self) -> Arc<Mutex<ClientSession>> {
-        self.client_session
-            .lock()
+    async fn get_reservation_repository(&self) -> Arc<dyn ReservationRepository + Send + Sync> {
+        self.reservation_repository.clone()
+    }
+
+    async fn get_processed_message_repository(
+        &self,
+    ) -> Arc<dyn ProcessedMessageRepository + Send + Sync> {
+        self.processed_message_repository.clone()
+    }
+
+    async fn begin_transaction(&self) -> Result<Arc<Mutex<ClientSession>>, DomainError> {
+        let mut session = self
+            .client
+            .start_session()
             .await
+            .map_err(|e| transaction_error("Failed to start session", e))?;
+
+        session
             .start_transaction()
             .await
-            .unwrap();
+            .map_err(|e| transaction_error("Failed to start transaction", e))?;
+
+        Ok(Arc::new(Mutex::new(session)))
+    }
+
+    async fn register_event(
+        &self,
+        event: Event,
+        session: Arc<Mutex<ClientSession>>,
+    ) -> Result<(), DomainError> {
+        let since_the_epoch = chrono::Utc::now().timestamp_millis();
+
+        let outbox_message = OutboxMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+            correlation_id: current_correlation_id(),
+            created_at_utc: since_the_epoch,
+            delivered_at_utc: None,
+            attempts: 0,
+            next_attempt_at_utc: since_the_epoch,
+            last_error: None,
+        };
 
-        self.client_session.clone()
+        event!(
+            Level::TRACE,
+            "registering {} {}",
+            outbox_message.event.event_type(),
+            outbox_message.id
+        );
+
+        self.outbox_repository
+            .create(outbox_message, session)
+            .await
+            .map(|_| ())
     }
 
-    async fn commit(&self) -> Result<(), String> {
+    // Committing again is safe when the outcome of the previous attempt is unknown
+    async fn commit(&self, session: Arc<Mutex<ClientSession>>) -> Result<(), DomainError> {
         event!(Level::TRACE, "Committing changes");
 
-        self.client_session
+        let mut guard = session.lock().await;
+        let mut attempt = 0;
+
+        loop {
+            match guard.commit_transaction().await {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                        && attempt < MAX_TRANSACTION_RETRIES =>
+                {
+                    attempt += 1;
+                    event!(
+                        Level::DEBUG,
+                        "Retrying commit with unknown result (attempt {}): {}",
+                        attempt,
+                        e
+                    );
+                }
+                Err(e) => return Err(transaction_error("Failed to commit transaction", e)),
+            }
+        }
+    }
+
+    async fn rollback(&self, session: Arc<Mutex<ClientSession>>) -> Result<(), DomainError> {
+        session
             .lock()
             .await
-            .commit_transaction()
+            .abort_transaction()
             .await
-            .unwrap();
-
-        let mut lock = self.events_to_publish.lock().await;
-        let mut event_results = Vec::new();
-        for e in lock.iter() {
-            event!(Level::TRACE, "publishing event");
-            event_results.push(
-                self.message_broker
-                    .publish_message(e, "product.created")
-                    .await,
-            );
+            .map_err(|e| transaction_error("Failed to abort transaction", e))
+    }
+}
+
+const MAX_TRANSACTION_RETRIES: u32 = 3;
+const TRANSACTION_RETRY_BASE_DELAY_MILLIS: u64 = 20;
+
+async fn retry_transient<T, F, Fut>(max_retries: u32, operation: F) -> Result<T, DomainError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, DomainError>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match operation().await {
+            Err(DomainError::Transient(e)) if attempt < max_retries => {
+                attempt += 1;
+                event!(
+                    Level::DEBUG,
+                    "Retrying transient transaction error (attempt {}): {}",
+                    attempt,
+                    e
+                );
+                tokio::time::sleep(Duration::from_millis(
+                    TRANSACTION_RETRY_BASE_DELAY_MILLIS * 2u64.pow(attempt),
+                ))
+                .await;
+            }
+            Err(DomainError::Transient(e)) => return Err(DomainError::Infrastructure(e)),
+            result => return result,
         }
+    }
+}
 
-        let mut single_event_failed = false;
-        for result in event_results {
-            let _ = match result {
-                Ok(()) => (),
+impl dyn UnitOfWork + Send + Sync {
+    /// Runs `operation` in a transaction on a fresh session, committing when it succeeds and
+    /// rolling back when it fails. Transactions Mongo aborts as transient are started over,
+    /// so `operation` must be safe to run more than once.
+    pub async fn run_in_transaction<T, F, Fut>(&self, operation: F) -> Result<T, DomainError>
+    where
+        F: Fn(Arc<Mutex<ClientSession>>) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, DomainError>> + Send,
+        T: Send,
+    {
+        retry_transient(MAX_TRANSACTION_RETRIES, || async {
+            let session = self.begin_transaction().await?;
+
+            match operation(session.clone()).await {
+                Ok(value) => self.commit(session).await.map(|_| value),
                 Err(e) => {
-                    single_event_failed = true;
-                    event!(Level::WARN, "event error found! {}", e);
+                    if let Err(rollback_error) = self.rollback(session).await {
+                        event!(
+                            Level::WARN,
+                            "Failed to roll back transaction: {}",
+                            rollback_error
+                        );
+                    }
+                    Err(e)
                 }
-            };
-        }
+            }
+        })
+        .await
+    }
+}
 
-        lock.clear();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
 
-        if single_event_failed {
-            return Err(String::from("Failed to commit changes."));
-        }
+    #[tokio::test]
+    async fn retry_transient_retries_until_operation_succeeds() {
+        // Arrange
+        let attempts = AtomicU32::new(0);
 
-        Ok(())
+        // Act
+        let result = retry_transient(MAX_TRANSACTION_RETRIES, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(DomainError::Transient(String::from("write conflict"))),
+                _ => Ok(()),
+            }
+        })
+        .await;
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
     }
 
-    async fn rollback(&self) -> Result<(), String> {
-        self.client_session
-            .lock()
-            .await
-            .abort_transaction()
-            .await
-            .unwrap();
+    #[tokio::test]
+    async fn retry_transient_reports_infrastructure_error_once_retries_are_used_up() {
+        // Arrange
+        let attempts = AtomicU32::new(0);
+
+        // Act
+        let result: Result<(), DomainError> = retry_transient(1, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DomainError::Transient(String::from("write conflict")))
+        })
+        .await;
+
+        // Assert
+        assert!(matches!(result, Err(DomainError::Infrastructure(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_does_not_retry_other_errors() {
+        // Arrange
+        let attempts = AtomicU32::new(0);
+
+        // Act
+        let result: Result<(), DomainError> = retry_transient(MAX_TRANSACTION_RETRIES, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DomainError::Conflict(String::from("stale version")))
+        })
+        .await;
 
-        Ok(())
+        // Assert
+        assert!(matches!(result, Err(DomainError::Conflict(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
     }
 }